@@ -0,0 +1,210 @@
+use crate::{Chatbot, Error};
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+/// Users who may control the bot, listed by ID or as members of a Slack user group. Nobody is an
+/// admin until some are configured.
+#[derive(Clone, Default)]
+pub struct Admins {
+    inner: Arc<RwLock<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    users: BTreeSet<String>,
+    usergroup: Option<String>,
+    members: BTreeSet<String>,
+}
+
+impl Admins {
+    pub fn add(&self, user: &str) {
+        self.inner.write().unwrap().users.insert(user.to_string());
+    }
+
+    /// Makes the members of a user group admins as well. Members are fetched by
+    /// [`Admins::refresh`].
+    pub fn set_usergroup(&self, usergroup: &str) {
+        self.inner.write().unwrap().usergroup = Some(usergroup.to_string());
+    }
+
+    /// Fetches the members of the user group, if there is one. Returns the number of admins.
+    pub async fn refresh(&self, slack: &slack::Client) -> Result<usize, Error> {
+        let usergroup = self.inner.read().unwrap().usergroup.clone();
+
+        if let Some(usergroup) = usergroup {
+            let members = slack.usergroup_members(&usergroup).await?;
+            info!(%usergroup, count = members.len(), "admin user group refreshed");
+
+            self.inner.write().unwrap().members = members.into_iter().collect();
+        }
+
+        Ok(self.count())
+    }
+
+    pub fn contains(&self, user: &str) -> bool {
+        let inner = self.inner.read().unwrap();
+        inner.users.contains(user) || inner.members.contains(user)
+    }
+
+    pub fn count(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        inner.users.union(&inner.members).count()
+    }
+}
+
+impl Chatbot {
+    pub fn admins(&self) -> &Admins {
+        &self.admins
+    }
+
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.admins.contains(user)
+    }
+
+    /// Returns a handle to this bot whose handlers only see messages from admins. These handlers
+    /// keep running while the bot is paused.
+    pub fn privileged(&self) -> Self {
+        Self {
+            privileged: true,
+            ..self.clone()
+        }
+    }
+
+    /// Stops delivering messages to handlers (other than privileged ones) and skips scheduled
+    /// tasks, until the bot is resumed.
+    pub fn pause(&self) {
+        info!("paused");
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        info!("resumed");
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// A summary of the bot's internal state.
+    pub fn status(&self) -> String {
+        let uptime = self.started.elapsed().as_secs();
+        let mut status = String::new();
+
+        writeln!(status, "paused: {}", self.is_paused()).unwrap();
+        writeln!(
+            status,
+            "uptime: {}d {}h {}m",
+            uptime / 86400,
+            uptime / 3600 % 24,
+            uptime / 60 % 60
+        )
+        .unwrap();
+        writeln!(status, "tasks: {}", self.supervisor.count()).unwrap();
+        writeln!(status, "sessions: {}", self.sessions.count()).unwrap();
+        writeln!(status, "admins: {}", self.admins.count()).unwrap();
+
+        for plugin in self.plugins.status() {
+            writeln!(status, "plugin {}", plugin).unwrap();
+        }
+
+        status
+    }
+
+    /// Adds the `@bot pause`, `@bot resume` and `@bot status` commands, for admins only.
+    pub fn admin_commands(&self) -> Result<&Self, Error> {
+        static COMMAND: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"(?i)^(pause|resume|status)$").unwrap());
+
+        self.privileged().listen(self.clone(), |bot, conn, msg| {
+            async move {
+                let command = match crate::command(conn, msg).and_then(|c| COMMAND.captures(c)) {
+                    Some(cap) => cap[1].to_lowercase(),
+                    None => return Ok(()),
+                };
+
+                let reply = match command.as_str() {
+                    "pause" => {
+                        bot.pause();
+                        "Paused. Say `resume` to bring me back.".to_string()
+                    }
+                    "resume" => {
+                        bot.resume();
+                        "Resumed.".to_string()
+                    }
+                    _ => format!("```{}```", bot.status()),
+                };
+
+                conn.post(&msg.channel, &reply, msg.thread_ts.as_ref())
+                    .await
+            }
+            .boxed()
+        })?;
+
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::Harness;
+
+    #[tokio::test]
+    async fn admins() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        harness.fake().set_usergroup("S1", &["U2"]);
+
+        bot.admins().add("U1");
+        bot.admins().set_usergroup("S1");
+        assert_eq!(bot.admins().refresh(&bot.slack()).await.unwrap(), 2);
+
+        assert!(bot.is_admin("U1"));
+        assert!(bot.is_admin("U2"));
+        assert!(!bot.is_admin("U3"));
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        bot.admins().add("U1");
+        bot.admin_commands().unwrap();
+        bot.reply("shrek", "SHREK").unwrap();
+
+        // Only admins can pause the bot.
+        harness.mention("C1", "U2", "pause");
+        harness.say("C1", "U2", "shrek");
+        harness.settle().await;
+        assert_eq!(harness.take_actions().len(), 1);
+
+        // Nor do commands count unless they come right after the mention.
+        harness.mention("C1", "U1", "what's your status?");
+        harness.settle().await;
+        assert_eq!(harness.take_actions().len(), 0);
+
+        harness.mention("C1", "U1", "pause");
+        harness.settle().await;
+        assert!(bot.is_paused());
+
+        harness.say("C1", "U2", "shrek");
+        harness.mention("C1", "U1", "status");
+        harness.settle().await;
+
+        let posts = harness.posts();
+        assert_eq!(posts.len(), 2);
+        assert!(posts[1].contains("paused: true"));
+
+        harness.mention("C1", "U1", "resume");
+        harness.settle().await;
+        harness.say("C1", "U2", "shrek");
+        harness.settle().await;
+        assert_eq!(harness.posts().last().unwrap(), "SHREK");
+    }
+}
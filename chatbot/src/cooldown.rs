@@ -0,0 +1,288 @@
+use slack::Message;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Token-bucket limits for a handler. Each limit allows `count` replies per `period`, refilling
+/// continuously, and bursting up to `count`. A reply is only allowed if every configured bucket
+/// (user, channel and global) has a token available.
+#[derive(Clone, Debug, Default)]
+pub struct Cooldown {
+    user: Option<Rate>,
+    channel: Option<Rate>,
+    global: Option<Rate>,
+    notice: Notice,
+}
+
+/// What to tell a user whose message went unanswered, e.g. because a handler is cooling down, or
+/// failed.
+#[derive(Clone, Debug, Default)]
+pub enum Notice {
+    #[default]
+    Silent,
+
+    /// React to the triggering message with the given emoji name.
+    React(String),
+
+    /// Post a message only visible to the triggering user.
+    Ephemeral(String),
+}
+
+impl Notice {
+    pub(crate) async fn send(
+        &self,
+        slack: &slack::Client,
+        msg: &Message,
+    ) -> Result<(), slack::Error> {
+        match self {
+            Notice::Silent => Ok(()),
+            Notice::React(emoji) => slack.react(msg, emoji).await,
+            Notice::Ephemeral(text) => {
+                let thread = msg.thread_ts.as_ref();
+                slack
+                    .post_ephemeral(&msg.channel, &msg.user, text, thread)
+                    .await
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Rate {
+    count: u32,
+    period: Duration,
+}
+
+impl Cooldown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn per_user(mut self, count: u32, period: Duration) -> Self {
+        self.user = Some(Rate { count, period });
+        self
+    }
+
+    pub fn per_channel(mut self, count: u32, period: Duration) -> Self {
+        self.channel = Some(Rate { count, period });
+        self
+    }
+
+    pub fn global(mut self, count: u32, period: Duration) -> Self {
+        self.global = Some(Rate { count, period });
+        self
+    }
+
+    pub fn notice(mut self, notice: Notice) -> Self {
+        self.notice = notice;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    cooldown: Cooldown,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    users: HashMap<String, Bucket>,
+    channels: HashMap<String, Bucket>,
+    global: Option<Bucket>,
+}
+
+impl Limiter {
+    pub(crate) fn new(cooldown: Cooldown) -> Self {
+        Self {
+            cooldown,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes a token for the message from every configured bucket, sending the configured notice
+    /// if any of them are empty. Returns whether the handler may reply.
+    pub(crate) async fn permit(&self, slack: &slack::Client, msg: &Message) -> bool {
+        if self.acquire(msg, Instant::now()) {
+            return true;
+        }
+
+        if let Err(error) = self.cooldown.notice.send(slack, msg).await {
+            warn!(%error, "failed to send cooldown notice");
+        }
+
+        false
+    }
+
+    /// Whether every configured bucket has a token for the message, without taking any. Lets a
+    /// handler skip expensive work for a reply that wouldn't be permitted anyway.
+    pub(crate) fn ready(&self, msg: &Message) -> bool {
+        self.check(msg, Instant::now(), false)
+    }
+
+    fn acquire(&self, msg: &Message, now: Instant) -> bool {
+        self.check(msg, now, true)
+    }
+
+    fn check(&self, msg: &Message, now: Instant, take: bool) -> bool {
+        let Cooldown {
+            user,
+            channel,
+            global,
+            ..
+        } = &self.cooldown;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets {
+            users,
+            channels,
+            global: global_bucket,
+        } = &mut *buckets;
+
+        // Forget buckets that have refilled completely, so that we don't keep one around for every
+        // user and channel we've ever seen.
+        if users.len() > 1024 {
+            users.retain(|_, b| !b.full(user.as_ref(), now));
+        }
+        if channels.len() > 1024 {
+            channels.retain(|_, b| !b.full(channel.as_ref(), now));
+        }
+
+        let mut limited = vec![];
+
+        if let Some(rate) = user {
+            limited.push((
+                users
+                    .entry(msg.user.clone())
+                    .or_insert_with(|| Bucket::new(rate, now)),
+                rate,
+            ));
+        }
+        if let Some(rate) = channel {
+            limited.push((
+                channels
+                    .entry(msg.channel.clone())
+                    .or_insert_with(|| Bucket::new(rate, now)),
+                rate,
+            ));
+        }
+        if let Some(rate) = global {
+            limited.push((
+                global_bucket.get_or_insert_with(|| Bucket::new(rate, now)),
+                rate,
+            ));
+        }
+
+        for (bucket, rate) in limited.iter_mut() {
+            bucket.refill(rate, now);
+        }
+
+        if limited.iter().any(|(bucket, _)| bucket.tokens < 1.0) {
+            return false;
+        }
+
+        if !take {
+            return true;
+        }
+
+        for (bucket, _) in limited.iter_mut() {
+            bucket.tokens -= 1.0;
+        }
+
+        true
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: &Rate, now: Instant) -> Self {
+        Self {
+            tokens: rate.count as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: &Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let per_sec = rate.count as f64 / rate.period.as_secs_f64().max(f64::EPSILON);
+
+        self.tokens = (self.tokens + elapsed * per_sec).min(rate.count as f64);
+        self.updated = now;
+    }
+
+    fn full(&mut self, rate: Option<&Rate>, now: Instant) -> bool {
+        match rate {
+            Some(rate) => {
+                self.refill(rate, now);
+                self.tokens >= rate.count as f64
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(user: &str, channel: &str) -> Message {
+        Message {
+            text: "echo hi".into(),
+            user: user.into(),
+            ts: "1636048583.000400".into(),
+            thread_ts: None,
+            reply_count: 0,
+            channel: channel.into(),
+            is_mention: false,
+            annotations: Default::default(),
+        }
+    }
+
+    #[test]
+    fn per_user_bucket_refills() {
+        let limiter = Limiter::new(Cooldown::new().per_user(2, Duration::from_secs(10)));
+        let now = Instant::now();
+
+        assert!(limiter.acquire(&message("alice", "general"), now));
+        assert!(limiter.acquire(&message("alice", "general"), now));
+        assert!(!limiter.acquire(&message("alice", "general"), now));
+        assert!(limiter.acquire(&message("bob", "general"), now));
+
+        let later = now + Duration::from_secs(5);
+        assert!(limiter.acquire(&message("alice", "general"), later));
+        assert!(!limiter.acquire(&message("alice", "general"), later));
+    }
+
+    #[test]
+    fn every_bucket_must_permit() {
+        let limiter = Limiter::new(
+            Cooldown::new()
+                .per_user(1, Duration::from_secs(3600))
+                .global(1, Duration::from_secs(60)),
+        );
+        let now = Instant::now();
+
+        assert!(limiter.acquire(&message("alice", "general"), now));
+        assert!(!limiter.acquire(&message("bob", "random"), now));
+
+        // The denied request must not have spent bob's user token, which takes an hour to refill.
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.acquire(&message("bob", "random"), later));
+    }
+
+    #[test]
+    fn checking_readiness_takes_nothing() {
+        let limiter = Limiter::new(Cooldown::new().per_user(1, Duration::from_secs(3600)));
+        let now = Instant::now();
+
+        assert!(limiter.check(&message("alice", "general"), now, false));
+        assert!(limiter.acquire(&message("alice", "general"), now));
+        assert!(!limiter.check(&message("alice", "general"), now, false));
+    }
+}
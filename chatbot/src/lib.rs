@@ -2,11 +2,49 @@ use futures::future::{BoxFuture, FutureExt};
 use futures::stream::{Stream, StreamExt};
 use regex::Regex;
 use slack::Message;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::broadcast;
-use tokio::task;
 use tokio_stream::wrappers::BroadcastStream;
-use tracing::{error, warn};
+use tracing::warn;
+
+mod admin;
+mod cooldown;
+mod middleware;
+mod optout;
+mod pacing;
+mod plugin;
+mod policy;
+mod report;
+mod rng;
+mod rules;
+mod schedule;
+pub mod session;
+mod shadow;
+mod store;
+mod supervisor;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+pub use admin::Admins;
+use cooldown::Limiter;
+pub use cooldown::{Cooldown, Notice};
+use middleware::Stack;
+pub use middleware::{Middleware, Outbound, Trace};
+pub use optout::OptOuts;
+pub use pacing::Pacing;
+use pacing::{Conversations, Pacer};
+pub use plugin::{Config, Health, Plugin, PluginError, Plugins, Setting, Status};
+pub use policy::{Policy, ALL};
+pub use report::ErrorPolicy;
+use report::Reporter;
+pub use rng::Rng;
+pub use rules::Rules;
+pub use schedule::{Missed, Schedule, Schedules};
+use session::Sessions;
+pub use shadow::Shadow;
+pub use supervisor::Supervisor;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -15,14 +53,53 @@ pub enum Error {
 
     #[error(transparent)]
     Slack(#[from] slack::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error("invalid rule: {0}")]
+    Rules(String),
+
+    #[error("invalid policy: {0}")]
+    Policy(String),
+
+    #[error("invalid schedule: {0}")]
+    Schedule(String),
+
+    #[error("plugin error: {0}")]
+    Plugin(String),
 }
 
 type Sender = broadcast::Sender<Arc<Message>>;
 
+#[derive(Clone)]
 pub struct Chatbot {
     slack: slack::Client,
     tx: Sender,
     raw_tx: Sender,
+    policy: Policy,
+    opt_outs: OptOuts,
+    schedules: Schedules,
+    rng: Rng,
+    name: Option<Arc<str>>,
+    cooldown: Option<Arc<Limiter>>,
+    pacer: Option<Arc<Pacer>>,
+    conversations: Conversations,
+    middleware: Stack,
+    plugins: Plugins,
+    supervisor: Supervisor,
+    sessions: Sessions,
+    reporter: Arc<Reporter>,
+    admins: Admins,
+    privileged: bool,
+    paused: Arc<AtomicBool>,
+    started: Instant,
 }
 
 impl Chatbot {
@@ -30,36 +107,155 @@ impl Chatbot {
         let (tx, _) = broadcast::channel(256);
         let (raw_tx, _) = broadcast::channel(256);
 
-        Ok(Self { slack, tx, raw_tx })
+        Ok(Self {
+            slack,
+            tx,
+            raw_tx,
+            policy: Policy::default(),
+            opt_outs: OptOuts::default(),
+            schedules: Schedules::default(),
+            rng: Rng::default(),
+            name: None,
+            cooldown: None,
+            pacer: None,
+            conversations: Conversations::default(),
+            middleware: Stack::default(),
+            plugins: Plugins::default(),
+            supervisor: Supervisor::default(),
+            sessions: Sessions::default(),
+            reporter: Arc::default(),
+            admins: Admins::default(),
+            privileged: false,
+            paused: Arc::default(),
+            started: Instant::now(),
+        })
     }
 
+    /// Returns a handle to this bot whose handlers are named, so that they can be toggled per
+    /// channel through the bot's [`Policy`]. Messages from channels where the handler is disabled
+    /// are filtered out of [`Chatbot::messages`].
+    pub fn named(&self, name: &str) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..self.clone()
+        }
+    }
+
+    /// The probability that a handler should trigger for the message, taking per-channel policy
+    /// overrides into account.
+    pub fn probability(&self, msg: &Message, default: f64) -> f64 {
+        self.name
+            .as_ref()
+            .and_then(|name| self.policy.probability(name, &msg.channel))
+            .unwrap_or(default)
+    }
+
+    /// Returns a handle to this bot whose reply handlers are rate limited by the given cooldown.
+    /// Every handler added through the returned handle shares the same buckets.
+    pub fn with_cooldown(&self, cooldown: Cooldown) -> Self {
+        Self {
+            cooldown: Some(Arc::new(Limiter::new(cooldown))),
+            ..self.clone()
+        }
+    }
+
+    /// Checks the message against this handle's cooldown, sending a notice if it has run out.
+    /// Handlers that don't reply through [`Chatbot::reply_all`] and friends should check this
+    /// before replying.
+    pub async fn permit(&self, msg: &Message) -> bool {
+        permit(&self.cooldown, &self.slack(), msg).await
+    }
+
+    pub fn rng(&self) -> &Rng {
+        &self.rng
+    }
+
+    /// A Slack client whose outbound actions pass through this handle's middleware.
     pub fn slack(&self) -> slack::Client {
-        self.slack.clone()
+        self.middleware.wrap(&self.slack)
     }
 
+    /// Messages for this handle's handlers. Messages that continue a [`session`] are left out,
+    /// as is everything while the bot is paused, unless the handle is [privileged], so that admin
+    /// commands always get through.
+    ///
+    /// [privileged]: Chatbot::privileged
     pub fn messages(&self) -> impl Stream<Item = Arc<Message>> {
-        subscribe(&self.tx)
+        let (sessions, privileged) = (self.sessions.clone(), self.privileged);
+
+        self.admit(self.delivered().filter(move |msg| {
+            let wanted = privileged || !sessions.claims(msg);
+            async move { wanted }
+        }))
+    }
+
+    // Checks messages against this handle's policy and the pause, then passes them through its
+    // inbound middleware.
+    fn admit(
+        &self,
+        messages: impl Stream<Item = Arc<Message>>,
+    ) -> impl Stream<Item = Arc<Message>> {
+        let policy = self.policy.clone();
+        let name = self.name.clone();
+        let middleware = self.middleware.clone();
+        let (admins, privileged, paused) =
+            (self.admins.clone(), self.privileged, self.paused.clone());
+
+        messages
+            .filter(move |msg| {
+                let enabled = match &name {
+                    Some(name) => policy.enabled(name, &msg.channel),
+                    None => true,
+                };
+                let allowed = if privileged {
+                    admins.contains(&msg.user)
+                } else {
+                    !paused.load(Ordering::Relaxed)
+                };
+                let wanted = enabled && allowed;
+
+                async move { wanted }
+            })
+            .filter_map(move |msg| {
+                let middleware = middleware.clone();
+                async move { middleware.inbound(msg).await }
+            })
+    }
+
+    // Every message delivered to handlers, i.e. not from the bot or opted out users.
+    fn delivered(&self) -> impl Stream<Item = Arc<Message>> {
+        subscribe(&self.tx).take_until(self.supervisor.stopped())
     }
 
     pub fn raw_messages(&self) -> impl Stream<Item = Arc<Message>> {
-        subscribe(&self.raw_tx)
+        subscribe(&self.raw_tx).take_until(self.supervisor.stopped())
     }
 
     pub fn reply_all<F>(&self, reply: F) -> Result<&Self, Error>
     where
         F: 'static + Sync + Send + Fn(&Message) -> Option<String>,
     {
-        self.listen(reply, move |reply, client, msg| {
-            async move {
-                if let Some(rep) = reply(msg) {
-                    let p = client.post(&msg.channel, &rep, msg.thread_ts.as_ref());
-                    p.await?;
-                }
+        self.listen(
+            (reply, self.cooldown.clone(), self.pacer.clone()),
+            move |(reply, cooldown, pacer), client, msg| {
+                async move {
+                    if !ready(cooldown, msg) {
+                        return Ok(());
+                    }
 
-                Ok::<(), slack::Error>(())
-            }
-            .boxed()
-        })
+                    if let Some(rep) = reply(msg) {
+                        if !permit(cooldown, client, msg).await {
+                            return Ok(());
+                        }
+
+                        self::reply(pacer, client, msg, &rep).await?;
+                    }
+
+                    Ok::<(), slack::Error>(())
+                }
+                .boxed()
+            },
+        )
     }
 
     pub fn reply_with<S, F>(&self, regex: S, reply: F) -> Result<&Self, Error>
@@ -69,10 +265,29 @@ impl Chatbot {
     {
         let re = Regex::new(regex.as_ref())?;
 
-        self.reply_all(move |msg| {
-            let captures = re.captures(&msg.text);
-            captures.map(|c| reply(msg, c)).flatten()
-        })
+        self.listen(
+            (re, reply, self.cooldown.clone(), self.pacer.clone()),
+            move |(re, reply, cooldown, pacer), client, msg| {
+                async move {
+                    let captures = match re.captures(&msg.text) {
+                        Some(captures) => captures,
+                        None => return Ok(()),
+                    };
+
+                    // The permit is only taken for a reply, and its notice only sent for one.
+                    if let Some(rep) = reply(msg, captures) {
+                        if !permit(cooldown, client, msg).await {
+                            return Ok(());
+                        }
+
+                        self::reply(pacer, client, msg, &rep).await?;
+                    }
+
+                    Ok::<(), slack::Error>(())
+                }
+                .boxed()
+            },
+        )
     }
 
     pub fn listen<T, F, E>(&self, context: T, action: F) -> Result<&Self, Error>
@@ -84,20 +299,29 @@ impl Chatbot {
             + Sync
             + 'static,
     {
-        let messages = self.messages();
-        let conn = self.slack();
+        let handler = Arc::new((context, action));
+        let bot = self.clone();
+        let name = self.name.as_deref().unwrap_or("listen");
 
-        task::spawn(async move {
-            let messages = messages.map(|m| (&action, &context, &conn, m));
+        self.supervisor.spawn(name, move || {
+            let (handler, bot) = (handler.clone(), bot.clone());
+            let messages = bot.messages();
+            let conn = bot.slack();
 
-            let f = messages.for_each_concurrent(None, |(action, context, conn, msg)| async move {
-                match action(context, conn, &msg).await {
-                    Ok(_) => (),
-                    Err(error) => error!(%error, "failure in listen loop"),
-                }
-            });
+            async move {
+                let (context, action) = &*handler;
+                let (bot, conn) = (&bot, &conn);
+
+                let f = messages.for_each_concurrent(None, |msg| async move {
+                    // Format the error first, since it may not be Send.
+                    let res = action(context, conn, &msg).await;
+                    if let Err(error) = res.map_err(|e| format!("{:#}", e)) {
+                        bot.report_error(Some(&msg), error).await;
+                    }
+                });
 
-            f.await;
+                f.await;
+            }
         });
 
         Ok(self)
@@ -108,17 +332,31 @@ impl Chatbot {
         T: Send + Sync + 'static,
         F: for<'a> Fn(&'a T, &'a Message) -> BoxFuture<'a, Option<String>> + 'static + Sync + Send,
     {
-        self.listen((context, reply), |(context, reply), conn, msg| {
-            async move {
-                if let Some(rep) = reply(context, msg).await {
-                    conn.post(&msg.channel, &rep, msg.thread_ts.as_ref())
-                        .await?
-                }
+        let (cooldown, pacer) = (self.cooldown.clone(), self.pacer.clone());
 
-                Ok::<(), slack::Error>(())
-            }
-            .boxed()
-        })
+        self.listen(
+            (context, reply, cooldown, pacer),
+            |(context, reply, cooldown, pacer), conn, msg| {
+                async move {
+                    // Whether there's a reply can't be known without doing the work, so only
+                    // skip the work if no reply could be permitted. The permit is taken after.
+                    if !ready(cooldown, msg) {
+                        return Ok(());
+                    }
+
+                    if let Some(rep) = reply(context, msg).await {
+                        if !permit(cooldown, conn, msg).await {
+                            return Ok(());
+                        }
+
+                        self::reply(pacer, conn, msg, &rep).await?
+                    }
+
+                    Ok::<(), slack::Error>(())
+                }
+                .boxed()
+            },
+        )
     }
 
     pub fn reply_with_async<S, F, T>(&self, regex: S, context: T, reply: F) -> Result<&Self, Error>
@@ -132,30 +370,54 @@ impl Chatbot {
     {
         let re = Regex::new(regex.as_ref())?;
 
-        self.reply_all_async((context, re, reply), |(context, re, reply), msg| {
-            async move {
-                match re.captures(&msg.text) {
-                    Some(capture) => reply(context, msg, capture).await,
-                    None => None,
+        let (cooldown, pacer) = (self.cooldown.clone(), self.pacer.clone());
+
+        self.listen(
+            (context, re, reply, cooldown, pacer),
+            |(context, re, reply, cooldown, pacer), conn, msg| {
+                async move {
+                    let captures = match re.captures(&msg.text) {
+                        Some(captures) => captures,
+                        None => return Ok(()),
+                    };
+
+                    // As with reply_all_async, the work is skipped if no reply could be
+                    // permitted, and the permit is only taken for a reply.
+                    if !ready(cooldown, msg) {
+                        return Ok(());
+                    }
+
+                    if let Some(rep) = reply(context, msg, captures).await {
+                        if !permit(cooldown, conn, msg).await {
+                            return Ok(());
+                        }
+
+                        self::reply(pacer, conn, msg, &rep).await?
+                    }
+
+                    Ok::<(), slack::Error>(())
                 }
-            }
-            .boxed()
-        })
+                .boxed()
+            },
+        )
     }
 
     pub fn reply(&self, regex: impl AsRef<str>, reply: &'static str) -> Result<&Self, Error> {
         self.reply_with(regex, |_, _| Some(reply.to_string()))
     }
 
+    /// Delivers messages to handlers until the stream ends or the bot stops.
     pub async fn run(&self, messages: impl Stream<Item = Message>) -> Result<(), Error> {
         messages
+            .take_until(self.supervisor.stopped())
             .for_each(|m| async move {
                 let msg = Arc::new(m);
 
                 // TODO: can we eliminate this clone?
                 self.raw_tx.send(msg.clone()).ok();
 
-                if msg.user != self.slack.bot_user_id() {
+                if msg.user != self.slack.bot_user_id() && !self.opt_outs.contains(&msg.user) {
+                    self.conversations.record(&msg);
                     self.tx.send(msg).ok();
                 }
             })
@@ -165,6 +427,41 @@ impl Chatbot {
     }
 }
 
+async fn permit(cooldown: &Option<Arc<Limiter>>, slack: &slack::Client, msg: &Message) -> bool {
+    match cooldown {
+        Some(limiter) => limiter.permit(slack, msg).await,
+        None => true,
+    }
+}
+
+/// The text of a command addressed to the bot, i.e. whatever follows a mention of the bot at the
+/// very start of the message.
+pub fn command<'a>(slack: &slack::Client, msg: &'a Message) -> Option<&'a str> {
+    let mention = format!("<@{}>", slack.bot_user_id());
+    let text = msg.text.trim_start().strip_prefix(&mention)?;
+
+    Some(text.trim())
+}
+
+fn ready(cooldown: &Option<Arc<Limiter>>, msg: &Message) -> bool {
+    match cooldown {
+        Some(limiter) => limiter.ready(msg),
+        None => true,
+    }
+}
+
+async fn reply(
+    pacer: &Option<Arc<Pacer>>,
+    slack: &slack::Client,
+    msg: &Message,
+    text: &str,
+) -> Result<(), slack::Error> {
+    match pacer {
+        Some(pacer) => pacer.reply(slack, msg, text).await,
+        None => slack.post(&msg.channel, text, msg.thread_ts.as_ref()).await,
+    }
+}
+
 fn subscribe(tx: &Sender) -> impl Stream<Item = Arc<Message>> {
     BroadcastStream::new(tx.subscribe()).filter_map(|res| async move {
         match res {
@@ -179,8 +476,155 @@ fn subscribe(tx: &Sender) -> impl Stream<Item = Arc<Message>> {
 
 #[cfg(test)]
 mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2, 4);
+    use super::*;
+    use crate::testing::{Action, Harness};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn replies_in_threads() {
+        let harness = Harness::new().await;
+        harness
+            .bot()
+            .reply_with("echo (.*)", |_, cap| Some(cap[1].to_string()))
+            .unwrap();
+
+        let parent = harness.say("C1", "U1", "echo hi");
+        harness.reply(&parent, "U2", "echo there");
+        harness.say("C1", "U1", "no echo");
+        harness.settle().await;
+
+        let mut actions = harness.actions();
+        actions.sort_by_key(|a| format!("{:?}", a));
+
+        assert_eq!(
+            actions,
+            [
+                Action::Post {
+                    channel: "C1".into(),
+                    text: "hi".into(),
+                    thread_ts: None,
+                },
+                Action::Post {
+                    channel: "C1".into(),
+                    text: "there".into(),
+                    thread_ts: Some(parent.ts),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_own_messages() {
+        let harness = Harness::new().await;
+        harness.bot().reply("shrek", "SHREK").unwrap();
+
+        harness.say("C1", testing::BOT_USER_ID, "shrek");
+        harness.settle().await;
+
+        assert!(harness.actions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cooldown_notice() {
+        let harness = Harness::new().await;
+        let cooldown = Cooldown::new()
+            .per_user(1, Duration::from_secs(3600))
+            .notice(Notice::React("ice_cube".into()));
+
+        harness
+            .bot()
+            .with_cooldown(cooldown)
+            .reply("shrek", "SHREK")
+            .unwrap();
+
+        harness.say("C1", "U1", "shrek");
+        harness.settle().await;
+        harness.say("C1", "U1", "shrek");
+        harness.settle().await;
+
+        assert_eq!(harness.posts(), ["SHREK"]);
+        assert_eq!(harness.reactions(), ["ice_cube"]);
+    }
+
+    #[tokio::test]
+    async fn cooldown_spent_only_on_replies() {
+        let harness = Harness::new().await;
+        let cooldown = Cooldown::new()
+            .per_user(1, Duration::from_secs(3600))
+            .notice(Notice::React("ice_cube".into()));
+
+        harness
+            .bot()
+            .with_cooldown(cooldown)
+            .reply_with("shrek (.*)", |_, cap| {
+                (&cap[1] != "no").then(|| "SHREK".to_string())
+            })
+            .unwrap();
+
+        harness.say("C1", "U1", "shrek no");
+        harness.settle().await;
+        harness.say("C1", "U1", "shrek yes");
+        harness.settle().await;
+
+        assert_eq!(harness.posts(), ["SHREK"]);
+        assert!(harness.reactions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cooldown_checked_before_work() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let harness = Harness::new().await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let limited = harness
+            .bot()
+            .with_cooldown(Cooldown::new().per_user(1, Duration::from_secs(3600)));
+
+        limited
+            .reply_all_async(calls.clone(), |calls, _| {
+                async move {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Some("expensive".to_string())
+                }
+                .boxed()
+            })
+            .unwrap();
+
+        harness.say("C1", "U1", "first");
+        harness.settle().await;
+        harness.say("C1", "U1", "second");
+        harness.settle().await;
+
+        assert_eq!(harness.posts(), ["expensive"]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn named_handlers_follow_policy() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        bot.named("cronk")
+            .reply_all(|msg| Some(format!("Cronk in {}", msg.channel)))
+            .unwrap();
+        bot.policy().disable("cronk", "SERIOUS").unwrap();
+
+        harness.say("SERIOUS", "U1", "hello");
+        harness.say("SILLY", "U1", "hello");
+        harness.settle().await;
+
+        assert_eq!(harness.posts(), ["Cronk in SILLY"]);
+    }
+
+    #[tokio::test]
+    async fn opted_out_users_are_ignored() {
+        let harness = Harness::new().await;
+        harness.bot().reply("shrek", "SHREK").unwrap();
+        harness.bot().opt_outs().opt_out("U1").unwrap();
+
+        harness.say("C1", "U1", "shrek");
+        harness.say("C1", "U2", "shrek");
+        harness.settle().await;
+
+        assert_eq!(harness.posts(), ["SHREK"]);
     }
 }
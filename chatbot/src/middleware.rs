@@ -0,0 +1,445 @@
+//! Middleware wraps handlers, so that cross-cutting concerns don't have to be repeated in each of
+//! them. Inbound middleware sees each message before a handler does, and outbound middleware sees
+//! everything a handler sends to Slack. Either can rewrite, annotate or drop what passes through.
+//!
+//! Middleware added with [`Chatbot::layer`] applies to handlers added through the returned
+//! handle, while middleware added with [`Chatbot::global_layer`] applies to every handler. Like a
+//! tower stack, global middleware is outermost: it sees inbound messages first and outbound
+//! actions last.
+
+use crate::Chatbot;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use slack::{Backend, Error, Message, Timestamp};
+use std::sync::{Arc, RwLock};
+use tokio::sync::watch;
+use tracing::debug;
+
+#[async_trait::async_trait]
+pub trait Middleware: Send + Sync + 'static {
+    /// Called with each message before the handler sees it. Returning `None` drops the message.
+    async fn inbound(&self, msg: Arc<Message>) -> Option<Arc<Message>> {
+        Some(msg)
+    }
+
+    /// Called with each action before it is sent to Slack. Returning `None` drops the action.
+    async fn outbound(&self, action: Outbound) -> Option<Outbound> {
+        Some(action)
+    }
+}
+
+/// Something a handler is sending to Slack.
+#[derive(Clone, Debug)]
+pub enum Outbound {
+    Post {
+        channel: String,
+        text: String,
+        thread_ts: Option<Timestamp>,
+    },
+    Ephemeral {
+        channel: String,
+        user: String,
+        text: String,
+        thread_ts: Option<Timestamp>,
+    },
+    Update {
+        channel: String,
+        ts: Timestamp,
+        text: String,
+    },
+    React {
+        message: Message,
+        emoji: String,
+    },
+    Upload {
+        parent: Message,
+        filename: String,
+        content: Bytes,
+    },
+}
+
+/// Logs everything that passes through it at debug level.
+pub struct Trace;
+
+#[async_trait::async_trait]
+impl Middleware for Trace {
+    async fn inbound(&self, msg: Arc<Message>) -> Option<Arc<Message>> {
+        debug!(channel=%msg.channel, user=%msg.user, ts=%msg.ts, "inbound message");
+        Some(msg)
+    }
+
+    async fn outbound(&self, action: Outbound) -> Option<Outbound> {
+        debug!(?action, "outbound action");
+        Some(action)
+    }
+}
+
+type Layers = Vec<Arc<dyn Middleware>>;
+
+#[derive(Clone, Default)]
+pub(crate) struct Stack {
+    global: Arc<RwLock<Layers>>,
+    local: Layers,
+}
+
+impl Stack {
+    fn layers(&self) -> Layers {
+        let global = self.global.read().unwrap();
+        global.iter().chain(&self.local).cloned().collect()
+    }
+
+    pub(crate) async fn inbound(&self, mut msg: Arc<Message>) -> Option<Arc<Message>> {
+        for layer in self.layers() {
+            msg = layer.inbound(msg).await?;
+        }
+
+        Some(msg)
+    }
+
+    async fn outbound(&self, mut action: Outbound) -> Option<Outbound> {
+        for layer in self.layers().iter().rev() {
+            action = layer.outbound(action).await?;
+        }
+
+        Some(action)
+    }
+
+    /// Wraps the client so that every outbound action passes through the stack. Global layers
+    /// added later still apply.
+    pub(crate) fn wrap(&self, slack: &slack::Client) -> slack::Client {
+        let layered = Layered {
+            inner: slack.backend().clone(),
+            stack: self.clone(),
+        };
+
+        slack.with_backend_replaced(Arc::new(layered))
+    }
+}
+
+struct Layered {
+    inner: Arc<dyn Backend>,
+    stack: Stack,
+}
+
+impl Layered {
+    // Returns the timestamp of the message posted, if the action was a post that wasn't dropped.
+    async fn send(&self, action: Outbound) -> Result<Option<Timestamp>, Error> {
+        let action = match self.stack.outbound(action).await {
+            Some(a) => a,
+            None => return Ok(None),
+        };
+
+        match action {
+            Outbound::Post {
+                channel,
+                text,
+                thread_ts,
+            } => {
+                let ts = self
+                    .inner
+                    .post_updatable(&channel, &text, thread_ts.as_ref())
+                    .await?;
+
+                return Ok(Some(ts));
+            }
+            Outbound::Ephemeral {
+                channel,
+                user,
+                text,
+                thread_ts,
+            } => {
+                self.inner
+                    .post_ephemeral(&channel, &user, &text, thread_ts.as_ref())
+                    .await?
+            }
+            Outbound::Update { channel, ts, text } => {
+                self.inner.update(&channel, &ts, &text).await?
+            }
+            Outbound::React { message, emoji } => self.inner.react(&message, &emoji).await?,
+            Outbound::Upload {
+                parent,
+                filename,
+                content,
+            } => self.inner.upload_reply(&parent, &filename, content).await?,
+        }
+
+        Ok(None)
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Layered {
+    async fn post(
+        &self,
+        channel: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<(), Error> {
+        self.send(Outbound::Post {
+            channel: channel.to_string(),
+            text: text.to_string(),
+            thread_ts: parent.cloned(),
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    // If middleware drops the post, the timestamp is empty, and updates to it are dropped too.
+    async fn post_updatable(
+        &self,
+        channel: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<Timestamp, Error> {
+        let ts = self
+            .send(Outbound::Post {
+                channel: channel.to_string(),
+                text: text.to_string(),
+                thread_ts: parent.cloned(),
+            })
+            .await?;
+
+        Ok(ts.unwrap_or_default())
+    }
+
+    async fn update(&self, channel: &str, ts: &Timestamp, text: &str) -> Result<(), Error> {
+        if ts.is_empty() {
+            return Ok(());
+        }
+
+        self.send(Outbound::Update {
+            channel: channel.to_string(),
+            ts: ts.clone(),
+            text: text.to_string(),
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn post_ephemeral(
+        &self,
+        channel: &str,
+        user: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<(), Error> {
+        self.send(Outbound::Ephemeral {
+            channel: channel.to_string(),
+            user: user.to_string(),
+            text: text.to_string(),
+            thread_ts: parent.cloned(),
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn emoji_list(&self) -> Result<Vec<String>, Error> {
+        self.inner.emoji_list().await
+    }
+
+    async fn react(&self, message: &Message, emoji: &str) -> Result<(), Error> {
+        self.send(Outbound::React {
+            message: message.clone(),
+            emoji: emoji.to_string(),
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn channel_ids(&self) -> Result<Vec<String>, Error> {
+        self.inner.channel_ids().await
+    }
+
+    async fn channel_history(&self, channel_id: &str) -> Result<Vec<Message>, Error> {
+        self.inner.channel_history(channel_id).await
+    }
+
+    async fn replies(&self, channel_id: &str, ts: &str) -> Result<Vec<Message>, Error> {
+        self.inner.replies(channel_id, ts).await
+    }
+
+    async fn display_name(&self, user_id: &str) -> Result<String, Error> {
+        self.inner.display_name(user_id).await
+    }
+
+    async fn usergroup_members(&self, usergroup_id: &str) -> Result<Vec<String>, Error> {
+        self.inner.usergroup_members(usergroup_id).await
+    }
+
+    async fn upload_reply(
+        &self,
+        parent: &Message,
+        filename: &str,
+        content: Bytes,
+    ) -> Result<(), Error> {
+        self.send(Outbound::Upload {
+            parent: parent.clone(),
+            filename: filename.to_string(),
+            content,
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn events(
+        &self,
+        tx: mpsc::UnboundedSender<Message>,
+        bot_user_id: &str,
+        closed: watch::Receiver<bool>,
+    ) -> Result<(), Error> {
+        self.inner.events(tx, bot_user_id, closed).await
+    }
+}
+
+impl Chatbot {
+    /// Returns a handle to this bot whose handlers are wrapped in the given middleware, inside any
+    /// middleware the handle already has.
+    pub fn layer(&self, middleware: impl Middleware) -> Self {
+        let mut bot = self.clone();
+        bot.middleware.local.push(Arc::new(middleware));
+        bot
+    }
+
+    /// Wraps every handler in the given middleware, including handlers that were already added.
+    pub fn global_layer(&self, middleware: impl Middleware) -> &Self {
+        let mut global = self.middleware.global.write().unwrap();
+        global.push(Arc::new(middleware));
+        drop(global);
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Action, Harness};
+
+    struct Shout;
+
+    #[async_trait::async_trait]
+    impl Middleware for Shout {
+        async fn inbound(&self, msg: Arc<Message>) -> Option<Arc<Message>> {
+            let mut msg = (*msg).clone();
+            msg.text = msg.text.to_uppercase();
+            Some(Arc::new(msg))
+        }
+    }
+
+    struct Tag(&'static str);
+
+    #[async_trait::async_trait]
+    impl Middleware for Tag {
+        async fn inbound(&self, msg: Arc<Message>) -> Option<Arc<Message>> {
+            let mut msg = (*msg).clone();
+            let tags = msg.annotations.entry("tags".into()).or_default();
+            tags.push_str(self.0);
+            Some(Arc::new(msg))
+        }
+
+        async fn outbound(&self, action: Outbound) -> Option<Outbound> {
+            match action {
+                Outbound::Post {
+                    channel,
+                    text,
+                    thread_ts,
+                } => Some(Outbound::Post {
+                    channel,
+                    text: format!("{}{}", text, self.0),
+                    thread_ts,
+                }),
+                other => Some(other),
+            }
+        }
+    }
+
+    struct Mute;
+
+    #[async_trait::async_trait]
+    impl Middleware for Mute {
+        async fn outbound(&self, action: Outbound) -> Option<Outbound> {
+            match action {
+                Outbound::Post { .. } => None,
+                other => Some(other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn per_handler_rewrite() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        bot.layer(Shout).reply("SHREK", "loud").unwrap();
+        bot.reply("SHREK", "quiet").unwrap();
+
+        harness.say("C1", "U1", "shrek");
+        harness.settle().await;
+
+        assert_eq!(harness.posts(), ["loud"]);
+    }
+
+    #[tokio::test]
+    async fn global_layers_are_outermost() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        bot.layer(Tag("b"))
+            .reply_all(|msg| Some(msg.annotations["tags"].clone()))
+            .unwrap();
+        bot.global_layer(Tag("a"));
+
+        harness.say("C1", "U1", "hi");
+        harness.settle().await;
+
+        // Inbound runs global then local, and outbound runs local then global.
+        assert_eq!(harness.posts(), ["abba"]);
+    }
+
+    #[tokio::test]
+    async fn drop_outbound() {
+        let harness = Harness::new().await;
+        let bot = harness.bot().layer(Mute);
+        bot.reply_all(|_| Some("hi".into())).unwrap();
+
+        let msg = harness.say("C1", "U1", "hello");
+        bot.slack().react(&msg, "wave").await.unwrap();
+        harness.settle().await;
+
+        assert!(harness.posts().is_empty());
+        assert_eq!(harness.reactions(), ["wave"]);
+    }
+
+    #[tokio::test]
+    async fn updates_follow_posts() {
+        let harness = Harness::new().await;
+        let slack = harness.bot().slack();
+
+        let ts = slack.post_updatable("C1", "hi", None).await.unwrap();
+        slack.update("C1", &ts, "hello").await.unwrap();
+
+        // Updates to posts that middleware dropped are dropped too.
+        let muted = harness.bot().layer(Mute).slack();
+        let ts = muted.post_updatable("C1", "shh", None).await.unwrap();
+        muted.update("C1", &ts, "shhh").await.unwrap();
+
+        assert_eq!(
+            harness.actions(),
+            [
+                Action::Post {
+                    channel: "C1".into(),
+                    text: "hi".into(),
+                    thread_ts: None,
+                },
+                Action::Update {
+                    channel: "C1".into(),
+                    ts: "1700000000.000000".into(),
+                    text: "hello".into(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use crate::store::Store;
+use crate::{Chatbot, Error};
+use futures::stream::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tracing::{error, info};
+
+/// Users who have asked the bot to leave them alone. Handlers never see messages from these users,
+/// and anything that stores or repeats messages (e.g. history) should check [`OptOuts::contains`].
+#[derive(Clone)]
+pub struct OptOuts {
+    inner: Arc<RwLock<Inner>>,
+    opted_out: broadcast::Sender<String>,
+}
+
+#[derive(Default)]
+struct Inner {
+    users: BTreeSet<String>,
+    store: Option<Arc<Store>>,
+}
+
+impl Default for OptOuts {
+    fn default() -> Self {
+        let (opted_out, _) = broadcast::channel(16);
+
+        Self {
+            inner: Arc::default(),
+            opted_out,
+        }
+    }
+}
+
+impl OptOuts {
+    /// Keeps opt outs in a JSON file, which needn't exist yet.
+    pub fn load(&self, path: impl Into<PathBuf>) -> Result<(), Error> {
+        let store = Store::new(path.into());
+        let users: BTreeSet<String> = store.load()?;
+
+        info!(path = ?store.path(), count = users.len(), "opt outs loaded");
+
+        let mut inner = self.inner.write().unwrap();
+        inner.users = users;
+        inner.store = Some(Arc::new(store));
+
+        Ok(())
+    }
+
+    pub fn contains(&self, user: &str) -> bool {
+        self.inner.read().unwrap().users.contains(user)
+    }
+
+    pub fn opt_out(&self, user: &str) -> Result<(), Error> {
+        self.update(|users| users.insert(user.to_string()))?;
+        self.opted_out.send(user.to_string()).ok();
+
+        Ok(())
+    }
+
+    pub fn opt_in(&self, user: &str) -> Result<(), Error> {
+        self.update(|users| users.remove(user))
+    }
+
+    /// A stream of users as they opt out, so that any data stored about them can be purged.
+    pub fn opted_out(&self) -> impl Stream<Item = String> {
+        BroadcastStream::new(self.opted_out.subscribe()).filter_map(|res| async move { res.ok() })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut BTreeSet<String>) -> bool) -> Result<(), Error> {
+        let mut inner = self.inner.write().unwrap();
+
+        if !f(&mut inner.users) {
+            return Ok(());
+        }
+
+        let store = match inner.store.clone() {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        let write = store.prepare(&inner.users)?;
+        drop(inner);
+
+        write.finish()
+    }
+}
+
+impl Chatbot {
+    pub fn opt_outs(&self) -> &OptOuts {
+        &self.opt_outs
+    }
+
+    /// Adds the `@bot optout` and `@bot optin` commands. These see every message, since opted out
+    /// users need to be able to opt back in.
+    pub fn opt_out_commands(&self) -> Result<&Self, Error> {
+        static COMMAND: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^opt\s?(out|in)$").unwrap());
+
+        let opt_outs = self.opt_outs.clone();
+        let bot = self.clone();
+
+        self.supervisor.spawn("opt_out_commands", move || {
+            let (opt_outs, conn) = (opt_outs.clone(), bot.slack());
+            let messages = bot.raw_messages();
+
+            async move {
+                let commands = messages.filter(|msg| {
+                    let command = msg.is_mention && msg.user != conn.bot_user_id();
+                    async move { command }
+                });
+
+                let (opt_outs, conn) = (&opt_outs, &conn);
+
+                commands
+                    .for_each(|msg| async move {
+                        let command = crate::command(conn, &msg);
+                        let cap = match command.and_then(|c| COMMAND.captures(c)) {
+                            Some(c) => c,
+                            None => return,
+                        };
+
+                        let (res, text) = match cap[1].to_lowercase().as_str() {
+                            "out" => (
+                                opt_outs.opt_out(&msg.user),
+                                "Got it, I'll leave you alone and forget what you've said. Say \
+                                 `optin` to change your mind.",
+                            ),
+                            _ => (opt_outs.opt_in(&msg.user), "Welcome back!"),
+                        };
+
+                        if let Err(error) = res {
+                            error!(%error, user=%msg.user, "failed to update opt outs");
+                            return;
+                        }
+
+                        let thread = msg.thread_ts.as_ref();
+                        if let Err(error) = conn
+                            .post_ephemeral(&msg.channel, &msg.user, text, thread)
+                            .await
+                        {
+                            error!(%error, "failed to confirm opt out");
+                        }
+                    })
+                    .await;
+            }
+        });
+
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Harness;
+
+    #[tokio::test]
+    async fn opt_out_and_in() {
+        let opt_outs = OptOuts::default();
+        let mut opted_out = Box::pin(opt_outs.opted_out());
+
+        assert!(!opt_outs.contains("U1"));
+
+        opt_outs.opt_out("U1").unwrap();
+        assert!(opt_outs.contains("U1"));
+        assert_eq!(opted_out.next().await.as_deref(), Some("U1"));
+
+        opt_outs.opt_in("U1").unwrap();
+        assert!(!opt_outs.contains("U1"));
+    }
+
+    #[tokio::test]
+    async fn opt_out_commands() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        bot.opt_out_commands().unwrap();
+
+        // Only the command itself counts, not a question about it.
+        harness.mention("C1", "U1", "how do I opt out?");
+        harness.settle().await;
+        assert!(!bot.opt_outs().contains("U1"));
+
+        harness.mention("C1", "U1", "opt out");
+        harness.settle().await;
+        assert!(bot.opt_outs().contains("U1"));
+
+        harness.mention("C1", "U1", "OPTIN");
+        harness.settle().await;
+        assert!(!bot.opt_outs().contains("U1"));
+        assert_eq!(harness.take_actions().len(), 2);
+    }
+}
@@ -0,0 +1,337 @@
+use crate::{Chatbot, Rng};
+use rand::Rng as _;
+use slack::{Message, Timestamp};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::debug;
+
+/// Delays replies as if they were being typed, taking longer for longer replies. Long replies can
+/// be split into several messages at sentence boundaries, each with its own delay. A reply that is
+/// still waiting when someone else speaks in the conversation is dropped.
+#[derive(Clone, Debug)]
+pub struct Pacing {
+    per_char: Duration,
+    max: Duration,
+    jitter: f64,
+    split: Option<usize>,
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Self {
+            per_char: Duration::from_millis(40),
+            max: Duration::from_secs(8),
+            jitter: 0.25,
+            split: None,
+        }
+    }
+}
+
+impl Pacing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long each character takes to type. Defaults to 40ms.
+    pub fn per_char(mut self, delay: Duration) -> Self {
+        self.per_char = delay;
+        self
+    }
+
+    /// The longest delay for a single message. Defaults to 8s.
+    pub fn max(mut self, delay: Duration) -> Self {
+        self.max = delay;
+        self
+    }
+
+    /// Varies each delay by up to this fraction of it, in either direction. Defaults to 0.25.
+    pub fn jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Splits replies with several sentences into messages of about `len` characters each.
+    pub fn split(mut self, len: usize) -> Self {
+        self.split = Some(len);
+        self
+    }
+}
+
+// A channel, and a thread in it if any.
+type Conversation = (String, Option<Timestamp>);
+
+// How many conversations are remembered. Past this, the quietest is forgotten, so a reply pending
+// there is posted even if the conversation moved on.
+const CONVERSATIONS: usize = 1024;
+
+/// The latest message in each conversation, recorded once any handle has pacing.
+#[derive(Clone, Default)]
+pub(crate) struct Conversations {
+    latest: Arc<Mutex<HashMap<Conversation, Timestamp>>>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl Conversations {
+    pub(crate) fn record(&self, msg: &Message) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let key = (msg.channel.clone(), msg.thread_ts.clone());
+        let mut latest = self.latest.lock().unwrap();
+
+        if !latest.contains_key(&key) && latest.len() >= CONVERSATIONS {
+            let quietest = latest
+                .iter()
+                .min_by(|a, b| a.1.cmp(b.1))
+                .map(|(k, _)| k.clone());
+            if let Some(quietest) = quietest {
+                latest.remove(&quietest);
+            }
+        }
+
+        let ts = latest.entry(key).or_insert_with(|| msg.ts.clone());
+
+        if *ts < msg.ts {
+            *ts = msg.ts.clone();
+        }
+    }
+
+    fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    // Whether anything was said in the message's conversation after it.
+    fn moved_on(&self, msg: &Message) -> bool {
+        let key = (msg.channel.clone(), msg.thread_ts.clone());
+        let latest = self.latest.lock().unwrap();
+        matches!(latest.get(&key), Some(ts) if *ts > msg.ts)
+    }
+}
+
+pub(crate) struct Pacer {
+    pacing: Pacing,
+    rng: Rng,
+    conversations: Conversations,
+}
+
+impl Pacer {
+    /// Posts the reply to the message in parts, unless the conversation moves on first.
+    pub(crate) async fn reply(
+        &self,
+        slack: &slack::Client,
+        msg: &Message,
+        text: &str,
+    ) -> Result<(), slack::Error> {
+        for part in self.parts(text) {
+            tokio::time::sleep(self.delay(&part)).await;
+
+            if self.conversations.moved_on(msg) {
+                debug!(channel = %msg.channel, ts = %msg.ts, "conversation moved on, dropping reply");
+                return Ok(());
+            }
+
+            slack
+                .post(&msg.channel, &part, msg.thread_ts.as_ref())
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    fn parts(&self, text: &str) -> Vec<String> {
+        match self.pacing.split {
+            _ if text.trim().is_empty() => vec![],
+            Some(len) if text.len() > len => chunks(text, len),
+            _ => vec![text.to_string()],
+        }
+    }
+
+    fn delay(&self, text: &str) -> Duration {
+        let Pacing {
+            per_char,
+            max,
+            jitter,
+            ..
+        } = self.pacing;
+
+        let delay = (per_char * text.chars().count() as u32).min(max);
+        let scale = self.rng.with(|rng| rng.gen_range(-jitter..=jitter));
+        delay.mul_f64(1.0 + scale)
+    }
+}
+
+// Groups sentences into chunks of about `len` characters. Sentences longer than `len` are left
+// whole.
+fn chunks(text: &str, len: usize) -> Vec<String> {
+    let mut chunks: Vec<String> = Vec::new();
+
+    for sentence in sentences(text) {
+        match chunks.last_mut() {
+            Some(chunk) if chunk.len() + sentence.len() < len => {
+                chunk.push(' ');
+                chunk.push_str(sentence);
+            }
+            _ => chunks.push(sentence.to_string()),
+        }
+    }
+
+    chunks
+}
+
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    let mut start = 0;
+
+    while let Some((idx, c)) = chars.next() {
+        let ends = matches!(c, '.' | '!' | '?')
+            && matches!(chars.peek(), Some((_, next)) if next.is_whitespace());
+
+        if ends {
+            sentences.push(text[start..=idx].trim());
+            start = idx + 1;
+        }
+    }
+
+    sentences.push(text[start..].trim());
+    sentences.retain(|s| !s.is_empty());
+    sentences
+}
+
+impl Chatbot {
+    /// Returns a handle to this bot whose replies are paced. This applies to handlers added with
+    /// [`Chatbot::reply_all`] and friends, and to [`Chatbot::respond`].
+    pub fn with_pacing(&self, pacing: Pacing) -> Self {
+        self.conversations.enable();
+
+        let pacer = Pacer {
+            pacing,
+            rng: self.rng.clone(),
+            conversations: self.conversations.clone(),
+        };
+
+        Self {
+            pacer: Some(Arc::new(pacer)),
+            ..self.clone()
+        }
+    }
+
+    /// Replies to the message, in its thread if it's in one, paced if this handle has pacing.
+    pub async fn respond(&self, msg: &Message, text: &str) -> Result<(), slack::Error> {
+        crate::reply(&self.pacer, &self.slack(), msg, text).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Harness;
+
+    fn pacing() -> Pacing {
+        Pacing::new().per_char(Duration::from_millis(2)).jitter(0.0)
+    }
+
+    #[test]
+    fn split_sentences() {
+        let text = "Ogres are like onions. They have layers!  Onions have layers? You get it.";
+
+        assert_eq!(
+            chunks(text, 30),
+            [
+                "Ogres are like onions.",
+                "They have layers!",
+                "Onions have layers?",
+                "You get it."
+            ]
+        );
+        assert_eq!(
+            chunks(text, 45),
+            [
+                "Ogres are like onions. They have layers!",
+                "Onions have layers? You get it."
+            ]
+        );
+        assert_eq!(chunks("No sentences here", 5), ["No sentences here"]);
+    }
+
+    #[tokio::test]
+    async fn splits_long_replies() {
+        let harness = Harness::new().await;
+        harness
+            .bot()
+            .with_pacing(pacing().split(20))
+            .reply("onions", "Ogres are like onions. They have layers.")
+            .unwrap();
+
+        harness.say("C1", "U1", "onions");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        harness.settle().await;
+
+        assert_eq!(
+            harness.posts(),
+            ["Ogres are like onions.", "They have layers."]
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_replies_when_conversation_moves_on() {
+        let harness = Harness::new().await;
+        harness
+            .bot()
+            .with_pacing(pacing().per_char(Duration::from_millis(20)))
+            .reply_all(|msg| Some(format!("re: {}", msg.text)))
+            .unwrap();
+
+        // A reply in a thread doesn't interrupt the channel.
+        let first = harness.say("C1", "U1", "first");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        harness.reply(&first, "U2", "aside");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        harness.say("C1", "U2", "second");
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        harness.settle().await;
+
+        let mut posts = harness.posts();
+        posts.sort();
+        assert_eq!(posts, ["re: aside", "re: second"]);
+    }
+
+    #[tokio::test]
+    async fn remembers_few_conversations() {
+        let harness = Harness::new().await;
+        let conversations = Conversations::default();
+
+        let first = harness.message("C0", "U1", "hi");
+        conversations.record(&first);
+        assert!(conversations.latest.lock().unwrap().is_empty());
+
+        conversations.enable();
+        for i in 0..=CONVERSATIONS {
+            conversations.record(&harness.message(&format!("C{}", i), "U1", "hi"));
+        }
+
+        let latest = conversations.latest.lock().unwrap();
+        assert_eq!(latest.len(), CONVERSATIONS);
+        assert!(!latest.contains_key(&("C0".to_string(), None)));
+    }
+
+    #[tokio::test]
+    async fn skips_empty_replies() {
+        let harness = Harness::new().await;
+        harness
+            .bot()
+            .with_pacing(pacing())
+            .reply_all(|_| Some(" ".to_string()))
+            .unwrap();
+
+        harness.say("C1", "U1", "hi");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        harness.settle().await;
+
+        assert!(harness.posts().is_empty());
+    }
+}
@@ -0,0 +1,424 @@
+use crate::report::chain;
+use crate::{Chatbot, Error};
+use futures::stream::StreamExt;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use slack::Message;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::info;
+
+pub type PluginError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A self-contained bot feature. The bot initializes each plugin with its configuration, delivers
+/// messages to it for as long as it's enabled, and shuts it down when the bot stops.
+///
+/// Hooks are passed a handle to the bot that is named after the plugin, so that the plugin follows
+/// the bot's [`Policy`](crate::Policy) for that name.
+#[async_trait::async_trait]
+pub trait Plugin: Send + Sync + 'static {
+    fn name(&self) -> &str;
+
+    /// The settings read from the plugin's configuration.
+    fn settings(&self) -> Vec<Setting> {
+        Vec::new()
+    }
+
+    /// Called once, before any other hook.
+    async fn init(&mut self, _bot: &Chatbot, _config: &Config) -> Result<(), PluginError> {
+        Ok(())
+    }
+
+    /// Called for each message while the plugin is enabled.
+    async fn on_message(&self, _bot: &Chatbot, _msg: &Message) -> Result<(), PluginError> {
+        Ok(())
+    }
+
+    /// Called once, when the bot stops.
+    async fn shutdown(&self) {}
+
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+}
+
+/// A setting in a plugin's configuration schema.
+#[derive(Clone, Debug)]
+pub struct Setting {
+    pub key: &'static str,
+    pub description: &'static str,
+
+    /// The value to use if the setting isn't configured. Settings without a default are required.
+    pub default: Option<&'static str>,
+}
+
+impl Setting {
+    pub fn required(key: &'static str, description: &'static str) -> Self {
+        Self {
+            key,
+            description,
+            default: None,
+        }
+    }
+
+    pub fn optional(key: &'static str, description: &'static str, default: &'static str) -> Self {
+        Self {
+            key,
+            description,
+            default: Some(default),
+        }
+    }
+}
+
+/// A plugin's configuration, checked against its settings.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    values: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Looks up every setting with `lookup`, falling back to defaults, and fails if a required
+    /// setting is missing.
+    pub fn resolve(
+        settings: &[Setting],
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Error> {
+        let mut values = BTreeMap::new();
+
+        for setting in settings {
+            let value = lookup(setting.key)
+                .or_else(|| setting.default.map(String::from))
+                .ok_or_else(|| {
+                    Error::Plugin(format!(
+                        "missing setting {} ({})",
+                        setting.key, setting.description
+                    ))
+                })?;
+
+            values.insert(setting.key.to_string(), value);
+        }
+
+        Ok(Self { values })
+    }
+
+    /// The value of a setting. Every setting in the plugin's schema has a value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    Degraded(String),
+    Unhealthy(String),
+}
+
+/// A snapshot of a plugin's state.
+#[derive(Clone, Debug)]
+pub struct Status {
+    pub name: String,
+    pub enabled: bool,
+    pub health: Health,
+
+    /// The number of hooks that have failed.
+    pub errors: u64,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.enabled { "on" } else { "off" };
+        write!(
+            f,
+            "{} ({}): {:?}, {} errors",
+            self.name, state, self.health, self.errors
+        )
+    }
+}
+
+struct Entry {
+    plugin: Arc<dyn Plugin>,
+    enabled: AtomicBool,
+    errors: AtomicU64,
+
+    // Cleared when a hook succeeds.
+    last_error: Mutex<Option<String>>,
+}
+
+impl Entry {
+    fn record<T>(&self, res: &Result<T, PluginError>) {
+        let mut last_error = self.last_error.lock().unwrap();
+
+        match res {
+            Ok(_) => *last_error = None,
+            Err(err) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                *last_error = Some(err.to_string());
+            }
+        }
+    }
+
+    fn status(&self) -> Status {
+        let health = match (self.plugin.health(), &*self.last_error.lock().unwrap()) {
+            (Health::Healthy, Some(err)) => Health::Degraded(err.clone()),
+            (health, _) => health,
+        };
+
+        Status {
+            name: self.plugin.name().to_string(),
+            enabled: self.enabled.load(Ordering::Relaxed),
+            health,
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The plugins added to a bot, in the order they were added.
+#[derive(Clone, Default)]
+pub struct Plugins {
+    entries: Arc<RwLock<Vec<Arc<Entry>>>>,
+}
+
+impl Plugins {
+    fn get(&self, name: &str) -> Result<Arc<Entry>, Error> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .iter()
+            .find(|e| e.plugin.name() == name)
+            .cloned()
+            .ok_or_else(|| Error::Plugin(format!("no plugin named {}", name)))
+    }
+
+    pub fn enable(&self, name: &str) -> Result<(), Error> {
+        self.get(name)?.enabled.store(true, Ordering::Relaxed);
+        info!(%name, "plugin enabled");
+        Ok(())
+    }
+
+    /// Stops delivering messages to the plugin until it's enabled again.
+    pub fn disable(&self, name: &str) -> Result<(), Error> {
+        self.get(name)?.enabled.store(false, Ordering::Relaxed);
+        info!(%name, "plugin disabled");
+        Ok(())
+    }
+
+    pub fn enabled(&self, name: &str) -> bool {
+        self.get(name)
+            .map(|e| e.enabled.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    pub fn status(&self) -> Vec<Status> {
+        let entries = self.entries.read().unwrap();
+        entries.iter().map(|e| e.status()).collect()
+    }
+
+    /// Shuts down every plugin, in the reverse of the order they were added.
+    pub async fn shutdown(&self) {
+        let entries = self.entries.read().unwrap().clone();
+
+        for entry in entries.iter().rev() {
+            entry.enabled.store(false, Ordering::Relaxed);
+            entry.plugin.shutdown().await;
+            info!(name = entry.plugin.name(), "plugin shut down");
+        }
+    }
+}
+
+impl Chatbot {
+    pub fn plugins(&self) -> &Plugins {
+        &self.plugins
+    }
+
+    /// Adds a plugin, configured from environment variables named after its settings.
+    pub async fn plugin(&self, plugin: impl Plugin) -> Result<&Self, Error> {
+        self.plugin_with(plugin, |key| std::env::var(key).ok())
+            .await
+    }
+
+    /// Adds a plugin, configured by looking up its settings with `lookup`.
+    pub async fn plugin_with(
+        &self,
+        mut plugin: impl Plugin,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<&Self, Error> {
+        let name = plugin.name().to_string();
+        let fail = |err: &dyn std::fmt::Display| Error::Plugin(format!("{}: {}", name, err));
+
+        if self.plugins.get(&name).is_ok() {
+            return Err(fail(&"already added"));
+        }
+
+        let bot = self.named(&name);
+        let config = Config::resolve(&plugin.settings(), lookup).map_err(|e| fail(&e))?;
+        plugin.init(&bot, &config).await.map_err(|e| fail(&e))?;
+
+        let entry = Arc::new(Entry {
+            plugin: Arc::new(plugin),
+            enabled: AtomicBool::new(true),
+            errors: AtomicU64::new(0),
+            last_error: Mutex::default(),
+        });
+        self.plugins.entries.write().unwrap().push(entry.clone());
+
+        info!(%name, "plugin initialized");
+
+        self.supervisor.spawn(&name, move || {
+            let (bot, entry) = (bot.clone(), entry.clone());
+            let messages = bot.messages();
+
+            async move {
+                let (bot, entry) = (&bot, &entry);
+
+                messages
+                    .filter(|_| {
+                        let enabled = entry.enabled.load(Ordering::Relaxed);
+                        async move { enabled }
+                    })
+                    .for_each_concurrent(None, |msg| async move {
+                        let res = entry.plugin.on_message(bot, &msg).await;
+                        if let Err(error) = &res {
+                            bot.report_error(Some(&msg), chain(error.as_ref())).await;
+                        }
+
+                        entry.record(&res);
+                    })
+                    .await;
+            }
+        });
+
+        Ok(self)
+    }
+
+    /// Adds the `@bot plugins` command, which lists plugins and their health, and the
+    /// `@bot plugin enable|disable <name>` commands, for admins only.
+    pub fn plugin_commands(&self) -> Result<&Self, Error> {
+        static COMMAND: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"(?i)^plugins?(?:\s+(enable|disable)\s+(\S+))?$").unwrap());
+
+        let admins = self.privileged();
+        admins.listen(self.plugins.clone(), |plugins, conn, msg| {
+            async move {
+                let cap = match crate::command(conn, msg).and_then(|c| COMMAND.captures(c)) {
+                    Some(c) => c,
+                    None => return Ok(()),
+                };
+
+                let reply = match (cap.get(1), cap.get(2)) {
+                    (Some(command), Some(name)) => {
+                        let command = command.as_str().to_lowercase();
+                        let name = name.as_str();
+
+                        let res = match command.as_str() {
+                            "enable" => plugins.enable(name),
+                            _ => plugins.disable(name),
+                        };
+
+                        match res {
+                            Ok(()) => format!("Done: {} {}.", command, name),
+                            Err(err) => format!("Couldn't {} {}: {}", command, name, err),
+                        }
+                    }
+                    _ => {
+                        let mut reply = String::new();
+                        for status in plugins.status() {
+                            writeln!(reply, "{}", status).unwrap();
+                        }
+
+                        reply
+                    }
+                };
+
+                if reply.is_empty() {
+                    return Ok(());
+                }
+
+                conn.post(&msg.channel, &reply, msg.thread_ts.as_ref())
+                    .await
+            }
+            .boxed()
+        })?;
+
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Harness;
+
+    #[derive(Default)]
+    struct Greeter {
+        greeting: String,
+    }
+
+    #[async_trait::async_trait]
+    impl Plugin for Greeter {
+        fn name(&self) -> &str {
+            "greeter"
+        }
+
+        fn settings(&self) -> Vec<Setting> {
+            vec![
+                Setting::required("GREETING", "what to say"),
+                Setting::optional("PUNCTUATION", "how to end it", "!"),
+            ]
+        }
+
+        async fn init(&mut self, _bot: &Chatbot, config: &Config) -> Result<(), PluginError> {
+            let (greeting, punc) = (config.get("GREETING"), config.get("PUNCTUATION"));
+            self.greeting = format!("{}{}", greeting.unwrap(), punc.unwrap());
+            Ok(())
+        }
+
+        async fn on_message(&self, bot: &Chatbot, msg: &Message) -> Result<(), PluginError> {
+            if msg.text == "fail" {
+                return Err("failed on purpose".into());
+            }
+
+            bot.slack().post(&msg.channel, &self.greeting, None).await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn lifecycle() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+
+        let missing = bot.plugin_with(Greeter::default(), |_| None).await;
+        assert!(missing.is_err());
+
+        let config = |key: &str| (key == "GREETING").then(|| "hello".to_string());
+        bot.plugin_with(Greeter::default(), config).await.unwrap();
+
+        harness.say("C1", "U1", "hi");
+        harness.settle().await;
+        assert_eq!(harness.posts(), ["hello!"]);
+
+        bot.plugins().disable("greeter").unwrap();
+        harness.say("C1", "U1", "hi");
+        harness.settle().await;
+        assert_eq!(harness.posts().len(), 1);
+
+        bot.plugins().enable("greeter").unwrap();
+        harness.say("C1", "U1", "fail");
+        harness.settle().await;
+
+        let status = &bot.plugins().status()[0];
+        assert!(status.enabled);
+        assert_eq!(status.errors, 1);
+        assert_eq!(status.health, Health::Degraded("failed on purpose".into()));
+
+        harness.say("C1", "U1", "hi");
+        harness.settle().await;
+        assert_eq!(bot.plugins().status()[0].health, Health::Healthy);
+
+        bot.plugins().shutdown().await;
+        assert!(!bot.plugins().enabled("greeter"));
+    }
+}
@@ -0,0 +1,369 @@
+use crate::store::Store;
+use crate::{Chatbot, Error};
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+/// Handler name that applies to every named handler.
+pub const ALL: &str = "*";
+
+/// Per-channel settings for named handlers. Handlers are enabled everywhere by default; a handler
+/// can be limited to an allow list of channels, disabled in specific channels, or have its trigger
+/// probability overridden per channel. Settings for [`ALL`] apply to every handler.
+///
+/// Probabilities can also be overridden for the current environment (e.g. always triggering in a
+/// test deployment), using [`Policy::override_probability`]. These overrides take precedence over
+/// per-channel settings, and are not persisted.
+#[derive(Clone, Default)]
+pub struct Policy {
+    inner: Arc<RwLock<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: State,
+    store: Option<Arc<Store>>,
+
+    // Keyed by handler and channel, either of which may be ALL.
+    overrides: BTreeMap<(String, String), f64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    #[serde(default)]
+    handlers: BTreeMap<String, HandlerPolicy>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HandlerPolicy {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    allow: BTreeSet<String>,
+
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    deny: BTreeSet<String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    probability: BTreeMap<String, f64>,
+}
+
+impl HandlerPolicy {
+    fn enabled(&self, channel: &str) -> bool {
+        if self.deny.contains(channel) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.contains(channel)
+    }
+}
+
+impl Policy {
+    /// Loads policy state from a JSON file. Later changes are saved to the same file.
+    pub fn load(&self, path: impl Into<PathBuf>) -> Result<(), Error> {
+        let store = Store::new(path.into());
+        let state: State = store.load()?;
+
+        info!(path = ?store.path(), handlers = state.handlers.len(), "policy loaded");
+
+        let mut inner = self.inner.write().unwrap();
+        inner.state = state;
+        inner.store = Some(Arc::new(store));
+
+        Ok(())
+    }
+
+    pub fn enabled(&self, handler: &str, channel: &str) -> bool {
+        let inner = self.inner.read().unwrap();
+        let handlers = &inner.state.handlers;
+
+        [ALL, handler]
+            .iter()
+            .filter_map(|h| handlers.get(*h))
+            .all(|p| p.enabled(channel))
+    }
+
+    /// The trigger probability for the handler in the channel, if it has been overridden.
+    pub fn probability(&self, handler: &str, channel: &str) -> Option<f64> {
+        let inner = self.inner.read().unwrap();
+        let handlers = &inner.state.handlers;
+
+        let overridden = [
+            (handler, channel),
+            (handler, ALL),
+            (ALL, channel),
+            (ALL, ALL),
+        ]
+        .iter()
+        .find_map(|(h, c)| inner.overrides.get(&(h.to_string(), c.to_string())));
+
+        if let Some(p) = overridden {
+            return Some(*p);
+        }
+
+        [handler, ALL]
+            .iter()
+            .filter_map(|h| handlers.get(*h))
+            .find_map(|p| p.probability.get(channel).copied())
+    }
+
+    /// Overrides the handler's trigger probability in the channel for as long as the bot runs.
+    /// Either may be [`ALL`].
+    pub fn override_probability(
+        &self,
+        handler: &str,
+        channel: &str,
+        probability: f64,
+    ) -> Result<(), Error> {
+        check_probability(probability)?;
+
+        let mut inner = self.inner.write().unwrap();
+        let key = (handler.to_string(), channel.to_string());
+        inner.overrides.insert(key, probability);
+
+        Ok(())
+    }
+
+    /// Applies overrides from a comma-separated list of `handler=probability` or
+    /// `handler@channel=probability` entries, e.g. `cronk=1.0,*@C0TEST=1.0`.
+    pub fn override_probabilities(&self, spec: &str) -> Result<(), Error> {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let invalid = || Error::Policy(format!("invalid probability override {:?}", entry));
+
+            let (target, probability) = entry.split_once('=').ok_or_else(invalid)?;
+            let probability = probability.trim().parse().map_err(|_| invalid())?;
+
+            let (handler, channel) = target
+                .trim()
+                .split_once('@')
+                .unwrap_or((target.trim(), ALL));
+            self.override_probability(handler, channel, probability)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn enable(&self, handler: &str, channel: &str) -> Result<(), Error> {
+        self.update(handler, |p| {
+            p.deny.remove(channel);
+            if !p.allow.is_empty() {
+                p.allow.insert(channel.to_string());
+            }
+        })
+    }
+
+    pub fn disable(&self, handler: &str, channel: &str) -> Result<(), Error> {
+        self.update(handler, |p| {
+            p.deny.insert(channel.to_string());
+        })
+    }
+
+    /// Limits the handler to the given channels. An empty list allows every channel.
+    pub fn allow_only<I, S>(&self, handler: &str, channels: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let channels = channels.into_iter().map(Into::into).collect();
+        self.update(handler, |p| p.allow = channels)
+    }
+
+    /// Overrides the handler's trigger probability in the channel, or restores the handler's
+    /// default if `None`.
+    pub fn set_probability(
+        &self,
+        handler: &str,
+        channel: &str,
+        probability: Option<f64>,
+    ) -> Result<(), Error> {
+        if let Some(p) = probability {
+            check_probability(p)?;
+        }
+
+        self.update(handler, |p| match probability {
+            Some(prob) => {
+                p.probability.insert(channel.to_string(), prob);
+            }
+            None => {
+                p.probability.remove(channel);
+            }
+        })
+    }
+
+    fn update(&self, handler: &str, f: impl FnOnce(&mut HandlerPolicy)) -> Result<(), Error> {
+        let mut inner = self.inner.write().unwrap();
+
+        f(inner.state.handlers.entry(handler.to_string()).or_default());
+
+        let store = match inner.store.clone() {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        // Every message checks the policy, so the lock isn't held while writing.
+        let write = store.prepare(&inner.state)?;
+        drop(inner);
+
+        write.finish()
+    }
+}
+
+fn check_probability(p: f64) -> Result<(), Error> {
+    if !(0.0..=1.0).contains(&p) {
+        return Err(Error::Policy(format!(
+            "probability {} is not between 0 and 1",
+            p
+        )));
+    }
+
+    Ok(())
+}
+
+impl Chatbot {
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Adds commands that change the policy for the channel they're sent in, e.g.
+    /// `@bot disable emoji`, `@bot enable gpt2` or `@bot probability cronk 0.5`. Use `*` to change
+    /// every handler, and `default` to clear a probability override. Only admins can use them.
+    pub fn policy_commands(&self) -> Result<&Self, Error> {
+        static COMMAND: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?i)^(enable|disable|probability)\s+(\S+)(?:\s+(default|[0-9.]+))?$")
+                .unwrap()
+        });
+
+        let admins = self.privileged();
+        admins.listen(self.policy.clone(), |policy, conn, msg| {
+            async move {
+                let cap = match crate::command(conn, msg).and_then(|c| COMMAND.captures(c)) {
+                    Some(c) => c,
+                    None => return Ok(()),
+                };
+
+                let command = cap[1].to_lowercase();
+                let handler = &cap[2];
+                let channel = &msg.channel;
+
+                let res = match (command.as_str(), cap.get(3).map(|m| m.as_str())) {
+                    ("enable", _) => policy.enable(handler, channel),
+                    ("disable", _) => policy.disable(handler, channel),
+                    ("probability", Some("default")) => {
+                        policy.set_probability(handler, channel, None)
+                    }
+                    ("probability", Some(p)) => match p.parse() {
+                        Ok(p) => policy.set_probability(handler, channel, Some(p)),
+                        Err(_) => Err(Error::Policy(format!("{} is not a probability", p))),
+                    },
+                    _ => Err(Error::Policy("missing probability".into())),
+                };
+
+                let reply = match res {
+                    Ok(()) => format!("Done: {} {}.", command, handler),
+                    Err(err) => format!("Couldn't {} {}: {}", command, handler, err),
+                };
+
+                conn.post(channel, &reply, msg.thread_ts.as_ref()).await
+            }
+            .boxed()
+        })?;
+
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Harness;
+
+    #[test]
+    fn allow_and_deny() {
+        let policy = Policy::default();
+
+        assert!(policy.enabled("emoji", "C1"));
+
+        policy.disable("emoji", "C1").unwrap();
+        assert!(!policy.enabled("emoji", "C1"));
+        assert!(policy.enabled("emoji", "C2"));
+        assert!(policy.enabled("gpt2", "C1"));
+
+        policy.allow_only("gpt2", ["C2"]).unwrap();
+        assert!(!policy.enabled("gpt2", "C1"));
+        assert!(policy.enabled("gpt2", "C2"));
+
+        policy.enable("gpt2", "C1").unwrap();
+        assert!(policy.enabled("gpt2", "C1"));
+
+        policy.disable(ALL, "C3").unwrap();
+        assert!(!policy.enabled("gpt2", "C3"));
+        assert!(!policy.enabled("emoji", "C3"));
+    }
+
+    #[test]
+    fn probability_overrides() {
+        let policy = Policy::default();
+
+        assert_eq!(policy.probability("emoji", "C1"), None);
+
+        policy.set_probability(ALL, "C1", Some(0.5)).unwrap();
+        policy.set_probability("emoji", "C1", Some(1.0)).unwrap();
+        assert_eq!(policy.probability("emoji", "C1"), Some(1.0));
+        assert_eq!(policy.probability("cronk", "C1"), Some(0.5));
+
+        policy.set_probability("emoji", "C1", None).unwrap();
+        assert_eq!(policy.probability("emoji", "C1"), Some(0.5));
+
+        assert!(policy.set_probability("emoji", "C1", Some(1.5)).is_err());
+    }
+
+    #[test]
+    fn environment_overrides() {
+        let policy = Policy::default();
+        policy.set_probability("cronk", "C1", Some(0.5)).unwrap();
+
+        policy
+            .override_probabilities("cronk=1.0, *@C2=0.25")
+            .unwrap();
+        assert_eq!(policy.probability("cronk", "C1"), Some(1.0));
+        assert_eq!(policy.probability("cronk", "C2"), Some(1.0));
+        assert_eq!(policy.probability("emoji", "C2"), Some(0.25));
+        assert_eq!(policy.probability("emoji", "C1"), None);
+
+        assert!(policy.override_probabilities("cronk").is_err());
+        assert!(policy.override_probabilities("cronk=lots").is_err());
+        assert!(policy.override_probabilities("cronk=2").is_err());
+    }
+
+    #[tokio::test]
+    async fn commands_do_not_collide() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        bot.admins().add("U1");
+        bot.policy_commands().unwrap();
+        bot.plugin_commands().unwrap();
+
+        harness.mention("C1", "U1", "plugin disable gpt2");
+        harness.settle().await;
+        assert_eq!(
+            harness.posts(),
+            ["Couldn't disable gpt2: plugin error: no plugin named gpt2"]
+        );
+        assert!(bot.policy().enabled("gpt2", "C1"));
+
+        harness.take_actions();
+        harness.mention("C1", "U1", "disable gpt2");
+        harness.settle().await;
+        assert_eq!(harness.posts(), ["Done: disable gpt2."]);
+        assert!(!bot.policy().enabled("gpt2", "C1"));
+
+        // Commands have to come right after the mention.
+        harness.take_actions();
+        harness.mention("C1", "U1", "what plugins do you have? enable gpt2 please");
+        harness.settle().await;
+        assert!(harness.posts().is_empty());
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{Chatbot, Notice};
+use slack::Message;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+/// What to do when a handler fails, besides logging the error.
+#[derive(Clone, Debug)]
+pub struct ErrorPolicy {
+    notice: Notice,
+    admin_channel: Option<String>,
+    dedup: Duration,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self {
+            notice: Notice::Silent,
+            admin_channel: None,
+            dedup: Duration::from_secs(3600),
+        }
+    }
+}
+
+impl ErrorPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What to tell the user whose message the handler failed on.
+    pub fn notice(mut self, notice: Notice) -> Self {
+        self.notice = notice;
+        self
+    }
+
+    /// Posts the full error chain to the given channel.
+    pub fn admin_channel(mut self, channel: &str) -> Self {
+        self.admin_channel = Some(channel.to_string());
+        self
+    }
+
+    /// Only posts an error to the admin channel once per `period`, if the same handler keeps
+    /// failing with the same error. Defaults to an hour.
+    pub fn dedup(mut self, period: Duration) -> Self {
+        self.dedup = period;
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Reporter {
+    policy: ErrorPolicy,
+
+    // Keyed by handler and error.
+    recent: Mutex<HashMap<(String, String), Recent>>,
+}
+
+#[derive(Debug)]
+struct Recent {
+    posted: Instant,
+    suppressed: u32,
+}
+
+impl Reporter {
+    pub(crate) fn new(policy: ErrorPolicy) -> Self {
+        Self {
+            policy,
+            recent: Mutex::default(),
+        }
+    }
+
+    pub(crate) async fn report(
+        &self,
+        slack: &slack::Client,
+        handler: &str,
+        msg: Option<&Message>,
+        error: &str,
+    ) {
+        error!(%error, %handler, "handler failed");
+
+        if let Some(msg) = msg {
+            if let Err(error) = self.policy.notice.send(slack, msg).await {
+                warn!(%error, "failed to send error notice");
+            }
+        }
+
+        let channel = match &self.policy.admin_channel {
+            Some(c) => c,
+            None => return,
+        };
+
+        let suppressed = match self.admit(handler, error, Instant::now()) {
+            Some(s) => s,
+            None => return,
+        };
+
+        let mut text = match msg {
+            Some(msg) => format!(
+                "`{}` failed on a message from <@{}> in <#{}>:",
+                handler, msg.user, msg.channel
+            ),
+            None => format!("`{}` failed:", handler),
+        };
+
+        text.push_str(&format!("\n```{}```", error));
+        if suppressed > 0 {
+            text.push_str(&format!(
+                "\n(also failed this way {} more times)",
+                suppressed
+            ));
+        }
+
+        if let Err(error) = slack.post(channel, &text, None).await {
+            warn!(%error, "failed to post error to admin channel");
+        }
+    }
+
+    // Returns the number of identical errors suppressed since the last one that was posted, or
+    // None if this one should be suppressed too.
+    fn admit(&self, handler: &str, error: &str, now: Instant) -> Option<u32> {
+        let mut recent = self.recent.lock().unwrap();
+        let dedup = self.policy.dedup;
+        recent.retain(|_, r| r.suppressed > 0 || now.duration_since(r.posted) < dedup);
+
+        let key = (handler.to_string(), error.to_string());
+        let fresh = Recent {
+            posted: now,
+            suppressed: 0,
+        };
+
+        match recent.get_mut(&key) {
+            Some(r) if now.duration_since(r.posted) < dedup => {
+                r.suppressed += 1;
+                None
+            }
+            Some(r) => Some(std::mem::replace(r, fresh).suppressed),
+            None => {
+                recent.insert(key, fresh);
+                Some(0)
+            }
+        }
+    }
+}
+
+/// Formats an error and its sources, e.g. `couldn't speak: HTTP 500`.
+pub(crate) fn chain(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut text = error.to_string();
+    let mut source = error.source();
+
+    while let Some(err) = source {
+        text.push_str(&format!(": {}", err));
+        source = err.source();
+    }
+
+    text
+}
+
+impl Chatbot {
+    /// Returns a handle to this bot whose handlers report failures according to the given policy.
+    pub fn with_error_policy(&self, policy: ErrorPolicy) -> Self {
+        Self {
+            reporter: Arc::new(Reporter::new(policy)),
+            ..self.clone()
+        }
+    }
+
+    /// Reports a handler failure according to this handle's [`ErrorPolicy`]. Errors are formatted
+    /// with `{:#}`, which includes the whole chain for e.g. `eyre` reports.
+    pub async fn report_error(&self, msg: Option<&Message>, error: impl Display) {
+        let handler = self.name.as_deref().unwrap_or("handler");
+        let error = format!("{:#}", error);
+
+        self.reporter
+            .report(&self.slack(), handler, msg, &error)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Action, Harness};
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn notice_and_admin_channel() {
+        let harness = Harness::new().await;
+        let policy = ErrorPolicy::new()
+            .notice(Notice::React("x".into()))
+            .admin_channel("ADMIN");
+
+        harness
+            .bot()
+            .with_error_policy(policy)
+            .named("speak")
+            .listen((), |_, _, _| async { Err("couldn't find parent") }.boxed())
+            .unwrap();
+
+        harness.say("C1", "U1", "speak");
+        harness.settle().await;
+        harness.say("C1", "U2", "speak");
+        harness.settle().await;
+
+        let admin: Vec<_> = harness
+            .actions()
+            .into_iter()
+            .filter(|a| matches!(a, Action::Post { channel, .. } if channel == "ADMIN"))
+            .collect();
+
+        assert_eq!(harness.reactions(), ["x", "x"]);
+        assert_eq!(admin.len(), 1);
+    }
+
+    #[test]
+    fn dedup() {
+        let reporter = Reporter::new(ErrorPolicy::new().dedup(Duration::from_secs(60)));
+        let now = Instant::now();
+
+        assert_eq!(reporter.admit("speak", "oops", now), Some(0));
+        assert_eq!(reporter.admit("speak", "oops", now), None);
+        assert_eq!(reporter.admit("speak", "uh oh", now), Some(0));
+        assert_eq!(reporter.admit("gpt2", "oops", now), Some(0));
+        assert_eq!(reporter.admit("speak", "oops", now), None);
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(reporter.admit("speak", "oops", later), Some(2));
+        assert_eq!(reporter.admit("speak", "uh oh", later), Some(0));
+    }
+}
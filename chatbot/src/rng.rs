@@ -0,0 +1,68 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng as _, SeedableRng};
+use std::sync::{Arc, Mutex};
+
+/// A shared random number generator. Handlers should use this rather than `thread_rng`, so that
+/// the bot's behavior can be made reproducible by seeding it.
+#[derive(Clone)]
+pub struct Rng {
+    inner: Arc<Mutex<StdRng>>,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(StdRng::from_entropy())),
+        }
+    }
+}
+
+impl Rng {
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+        }
+    }
+
+    /// Reseeds the generator in place, affecting every clone of this handle.
+    pub fn reseed(&self, seed: u64) {
+        *self.inner.lock().unwrap() = StdRng::seed_from_u64(seed);
+    }
+
+    /// Returns true with probability `p`, which must be between 0 and 1.
+    pub fn gen_bool(&self, p: f64) -> bool {
+        self.with(|rng| rng.gen_bool(p))
+    }
+
+    pub fn choose<'a, T>(&self, items: &'a [T]) -> Option<&'a T> {
+        self.with(|rng| items.choose(rng))
+    }
+
+    /// Runs `f` with exclusive access to the underlying generator.
+    pub fn with<R>(&self, f: impl FnOnce(&mut StdRng) -> R) -> R {
+        f(&mut self.inner.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_is_reproducible() {
+        let a = Rng::seeded(7);
+        let b = a.clone();
+        let c = Rng::seeded(7);
+
+        let items: Vec<u32> = (0..100).collect();
+        let first: Vec<_> = (0..10).map(|_| *a.choose(&items).unwrap()).collect();
+        let second: Vec<_> = (0..10).map(|_| *c.choose(&items).unwrap()).collect();
+        assert_eq!(first, second);
+
+        // Clones share their state, and reseeding applies to all of them.
+        b.reseed(7);
+        let third: Vec<_> = (0..10).map(|_| *a.choose(&items).unwrap()).collect();
+        assert_eq!(first, third);
+    }
+}
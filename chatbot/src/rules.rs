@@ -0,0 +1,379 @@
+use crate::{permit, Chatbot, Error, Policy, Rng, Supervisor};
+use futures::FutureExt;
+use regex::Regex;
+use serde::Deserialize;
+use slack::Message;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info};
+
+/// How often the rules file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Simple reply rules loaded from a TOML file, e.g.
+///
+/// ```toml
+/// [[rule]]
+/// pattern = "(?i)give (him|her|them) the (.*)"
+/// reply = "don't give $1 the $2"
+/// uppercase = true
+///
+/// [[rule]]
+/// name = "cronk"
+/// replies = ["Cronk.", "Buy Cronk."]
+/// probability = 0.01
+/// exclude_channels = ["C0SERIOUS"]
+/// ```
+///
+/// Replies may refer to capture groups using `$1` or `$name` syntax. Rules without a pattern
+/// match every message. Named rules can be toggled per channel through the bot's [`Policy`], like
+/// any other named handler.
+#[derive(Clone)]
+pub struct Rules {
+    path: Arc<PathBuf>,
+    rules: Arc<RwLock<Vec<Rule>>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    name: Option<String>,
+    pattern: Option<String>,
+    reply: Option<String>,
+
+    #[serde(default)]
+    replies: Vec<String>,
+
+    #[serde(default = "always")]
+    probability: f64,
+
+    #[serde(default)]
+    uppercase: bool,
+
+    #[serde(default)]
+    channels: Vec<String>,
+
+    #[serde(default)]
+    exclude_channels: Vec<String>,
+}
+
+fn always() -> f64 {
+    1.0
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: Option<String>,
+    pattern: Option<Regex>,
+    replies: Vec<String>,
+    probability: f64,
+    uppercase: bool,
+    channels: Vec<String>,
+    exclude_channels: Vec<String>,
+}
+
+impl Rule {
+    fn new(config: RuleConfig) -> Result<Self, Error> {
+        let mut replies = config.replies;
+        replies.extend(config.reply);
+
+        if replies.is_empty() {
+            return Err(Error::Rules("rule has no replies".into()));
+        }
+
+        if !(0.0..=1.0).contains(&config.probability) {
+            return Err(Error::Rules(format!(
+                "probability {} is not between 0 and 1",
+                config.probability
+            )));
+        }
+
+        Ok(Self {
+            name: config.name,
+            pattern: config.pattern.as_deref().map(Regex::new).transpose()?,
+            replies,
+            probability: config.probability,
+            uppercase: config.uppercase,
+            channels: config.channels,
+            exclude_channels: config.exclude_channels,
+        })
+    }
+
+    fn reply(&self, msg: &Message, policy: &Policy, rng: &Rng) -> Option<String> {
+        if let Some(name) = &self.name {
+            if !policy.enabled(name, &msg.channel) {
+                return None;
+            }
+        }
+
+        if !self.channels.is_empty() && !self.channels.contains(&msg.channel) {
+            return None;
+        }
+
+        if self.exclude_channels.contains(&msg.channel) {
+            return None;
+        }
+
+        let captures = match &self.pattern {
+            Some(re) => Some(re.captures(&msg.text)?),
+            None => None,
+        };
+
+        let probability = self
+            .name
+            .as_ref()
+            .and_then(|name| policy.probability(name, &msg.channel))
+            .unwrap_or(self.probability);
+
+        if !rng.gen_bool(probability) {
+            return None;
+        }
+
+        let template = rng.choose(&self.replies)?;
+
+        let mut reply = String::new();
+        match captures {
+            Some(c) => c.expand(template, &mut reply),
+            None => reply.push_str(template),
+        }
+
+        if self.uppercase {
+            reply = reply.to_uppercase();
+        }
+
+        Some(reply)
+    }
+}
+
+impl Rules {
+    fn load(path: &Path) -> Result<Vec<Rule>, Error> {
+        let text = std::fs::read_to_string(path)?;
+        let file: RulesFile = toml::from_str(&text)?;
+
+        file.rule.into_iter().map(Rule::new).collect()
+    }
+
+    /// Re-reads the rules file. The previous rules stay in place if the new ones are invalid.
+    pub fn reload(&self) -> Result<usize, Error> {
+        let rules = Self::load(&self.path)?;
+        let count = rules.len();
+
+        *self.rules.write().unwrap() = rules;
+        info!(path=?self.path, count, "rules loaded");
+
+        Ok(count)
+    }
+
+    fn replies(&self, msg: &Message, policy: &Policy, rng: &Rng) -> Vec<String> {
+        let rules = self.rules.read().unwrap();
+        rules
+            .iter()
+            .filter_map(|r| r.reply(msg, policy, rng))
+            .collect()
+    }
+
+    // Reload the rules whenever the file's modification time changes.
+    async fn watch(self, supervisor: Supervisor, mut modified: Option<SystemTime>) {
+        while supervisor.sleep(POLL_INTERVAL).await {
+            let current = tokio::fs::metadata(self.path.as_path())
+                .await
+                .and_then(|m| m.modified())
+                .ok();
+
+            if current == modified {
+                continue;
+            }
+
+            debug!(path=?self.path, "rules file changed");
+            modified = current;
+
+            if let Err(error) = self.reload() {
+                error!(%error, "failed to reload rules, keeping previous rules");
+            }
+        }
+    }
+}
+
+impl Chatbot {
+    /// Adds a handler that replies according to the rules in the given TOML file. The file is
+    /// watched for changes, and can also be reloaded using the returned handle.
+    pub fn rules(&self, path: impl Into<PathBuf>) -> Result<Rules, Error> {
+        let path = path.into();
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+        let rules = Rules {
+            rules: Arc::new(RwLock::new(Rules::load(&path)?)),
+            path: Arc::new(path),
+        };
+
+        let (watched, supervisor) = (rules.clone(), self.supervisor.clone());
+        self.supervisor.spawn("rules_watcher", move || {
+            watched.clone().watch(supervisor.clone(), modified)
+        });
+
+        let context = (
+            rules.clone(),
+            self.policy.clone(),
+            self.rng.clone(),
+            self.cooldown.clone(),
+        );
+
+        self.listen(context, |(rules, policy, rng, cooldown), conn, msg| {
+            async move {
+                for rep in rules.replies(msg, policy, rng) {
+                    if permit(cooldown, conn, msg).await {
+                        conn.post(&msg.channel, &rep, msg.thread_ts.as_ref())
+                            .await?;
+                    }
+                }
+
+                Ok::<(), slack::Error>(())
+            }
+            .boxed()
+        })?;
+
+        Ok(rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str, channel: &str) -> Message {
+        Message {
+            text: text.into(),
+            user: "UL6H0F39R".into(),
+            ts: "1636048583.000400".into(),
+            thread_ts: None,
+            reply_count: 0,
+            channel: channel.into(),
+            is_mention: false,
+            annotations: Default::default(),
+        }
+    }
+
+    fn rules(text: &str) -> Vec<Rule> {
+        let file: RulesFile = toml::from_str(text).unwrap();
+        file.rule
+            .into_iter()
+            .map(|r| Rule::new(r).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn expands_captures() {
+        let rules = rules(
+            r#"
+            [[rule]]
+            pattern = "(?i)give (him|her|them) the (.*)"
+            reply = "don't give $1 the $2"
+            uppercase = true
+            "#,
+        );
+
+        let reply = rules[0].reply(
+            &message("give them the onion", "C1"),
+            &Policy::default(),
+            &Rng::default(),
+        );
+        assert_eq!(reply.as_deref(), Some("DON'T GIVE THEM THE ONION"));
+        assert_eq!(
+            rules[0].reply(
+                &message("take the onion", "C1"),
+                &Policy::default(),
+                &Rng::default()
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn filters_channels() {
+        let rules = rules(
+            r#"
+            [[rule]]
+            pattern = "^shrek no$"
+            reply = "SHREK YES"
+            exclude_channels = ["SERIOUS"]
+
+            [[rule]]
+            reply = "Cronk."
+            channels = ["SILLY"]
+            "#,
+        );
+
+        assert!(rules[0]
+            .reply(
+                &message("shrek no", "SERIOUS"),
+                &Policy::default(),
+                &Rng::default()
+            )
+            .is_none());
+        assert!(rules[0]
+            .reply(
+                &message("shrek no", "SILLY"),
+                &Policy::default(),
+                &Rng::default()
+            )
+            .is_some());
+        assert!(rules[1]
+            .reply(
+                &message("anything", "SERIOUS"),
+                &Policy::default(),
+                &Rng::default()
+            )
+            .is_none());
+        assert!(rules[1]
+            .reply(
+                &message("anything", "SILLY"),
+                &Policy::default(),
+                &Rng::default()
+            )
+            .is_some());
+    }
+
+    #[test]
+    fn named_rules_follow_policy() {
+        let rules = rules(
+            r#"
+            [[rule]]
+            name = "cronk"
+            reply = "Cronk."
+            probability = 0.0
+            "#,
+        );
+
+        let policy = Policy::default();
+        assert!(rules[0]
+            .reply(&message("anything", "C1"), &policy, &Rng::default())
+            .is_none());
+
+        policy.set_probability("cronk", "C1", Some(1.0)).unwrap();
+        assert!(rules[0]
+            .reply(&message("anything", "C1"), &policy, &Rng::default())
+            .is_some());
+
+        policy.disable("cronk", "C1").unwrap();
+        assert!(rules[0]
+            .reply(&message("anything", "C1"), &policy, &Rng::default())
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let file: RulesFile = toml::from_str("[[rule]]\npattern = \"shrek\"").unwrap();
+        assert!(file.rule.into_iter().map(Rule::new).all(|r| r.is_err()));
+
+        let file: RulesFile = toml::from_str("[[rule]]\nreply = \"x\"\nprobability = 2.0").unwrap();
+        assert!(file.rule.into_iter().map(Rule::new).all(|r| r.is_err()));
+    }
+}
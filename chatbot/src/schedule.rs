@@ -0,0 +1,313 @@
+use crate::store::Store;
+use crate::{Chatbot, Error};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use futures::future::{BoxFuture, FutureExt};
+use rand::Rng as _;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+/// When a scheduled task should run.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    name: String,
+    when: When,
+    jitter: Duration,
+    missed: Missed,
+}
+
+#[derive(Clone, Debug)]
+enum When {
+    Every(chrono::Duration),
+    Cron(Box<cron::Schedule>, Tz),
+}
+
+/// What to do about runs that were missed while the bot was down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Missed {
+    /// Wait for the next scheduled run.
+    Skip,
+
+    /// Run once as soon as the bot starts, no matter how many runs were missed.
+    RunOnce,
+}
+
+impl Schedule {
+    /// Runs every `period`. The name identifies the schedule across restarts.
+    pub fn every(name: &str, period: Duration) -> Result<Self, Error> {
+        let period = chrono::Duration::from_std(period)
+            .ok()
+            .filter(|p| *p > chrono::Duration::zero())
+            .ok_or_else(|| Error::Schedule(format!("invalid period {:?}", period)))?;
+
+        Ok(Self::new(name, When::Every(period)))
+    }
+
+    /// Runs according to a cron expression (with seconds, e.g. `0 0 9 * * Mon`), evaluated in
+    /// the given time zone (e.g. `America/Chicago`).
+    pub fn cron(name: &str, expression: &str, time_zone: &str) -> Result<Self, Error> {
+        let schedule = cron::Schedule::from_str(expression)
+            .map_err(|err| Error::Schedule(format!("{}: {}", expression, err)))?;
+        let tz = Tz::from_str(time_zone)
+            .map_err(|err| Error::Schedule(format!("{}: {}", time_zone, err)))?;
+
+        Ok(Self::new(name, When::Cron(Box::new(schedule), tz)))
+    }
+
+    fn new(name: &str, when: When) -> Self {
+        Self {
+            name: name.to_string(),
+            when,
+            jitter: Duration::ZERO,
+            missed: Missed::Skip,
+        }
+    }
+
+    /// Delays each run by a random amount up to `jitter`.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn missed(mut self, missed: Missed) -> Self {
+        self.missed = missed;
+        self
+    }
+
+    /// The first scheduled time strictly after `time`.
+    fn after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match &self.when {
+            When::Every(period) => Some(time + *period),
+            When::Cron(schedule, tz) => schedule
+                .after(&time.with_timezone(tz))
+                .next()
+                .map(|t| t.with_timezone(&Utc)),
+        }
+    }
+
+    /// The next time to run, given the last time this schedule ran (if ever).
+    fn next(&self, last: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let last = match last {
+            Some(last) => last,
+            None => return self.after(now),
+        };
+
+        match self.after(last) {
+            Some(next) if next < now && self.missed == Missed::RunOnce => Some(now),
+            Some(next) if next < now => self.after(now),
+            next => next,
+        }
+    }
+}
+
+/// Last run times for every schedule, so that schedules pick up where they left off after a
+/// restart.
+#[derive(Clone, Default)]
+pub struct Schedules {
+    inner: Arc<RwLock<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Unix timestamps, in seconds.
+    last_run: BTreeMap<String, i64>,
+    store: Option<Arc<Store>>,
+}
+
+impl Schedules {
+    /// Remembers when each schedule last ran in a JSON file, so that missed runs can be caught up
+    /// on after a restart.
+    pub fn load(&self, path: impl Into<PathBuf>) -> Result<(), Error> {
+        let store = Store::new(path.into());
+        let last_run: BTreeMap<String, i64> = store.load()?;
+
+        info!(path = ?store.path(), count = last_run.len(), "schedules loaded");
+
+        let mut inner = self.inner.write().unwrap();
+        inner.last_run = last_run;
+        inner.store = Some(Arc::new(store));
+
+        Ok(())
+    }
+
+    fn last_run(&self, name: &str) -> Option<DateTime<Utc>> {
+        let inner = self.inner.read().unwrap();
+        let secs = *inner.last_run.get(name)?;
+
+        DateTime::from_timestamp(secs, 0)
+    }
+
+    fn record(&self, name: &str, time: DateTime<Utc>) -> Result<(), Error> {
+        let mut inner = self.inner.write().unwrap();
+        inner.last_run.insert(name.to_string(), time.timestamp());
+
+        let store = match inner.store.clone() {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        let write = store.prepare(&inner.last_run)?;
+        drop(inner);
+
+        write.finish()
+    }
+}
+
+impl Chatbot {
+    pub fn schedules(&self) -> &Schedules {
+        &self.schedules
+    }
+
+    /// Runs `action` according to the schedule, for as long as the bot is running.
+    pub fn schedule<T, F, E>(
+        &self,
+        schedule: Schedule,
+        context: T,
+        action: F,
+    ) -> Result<&Self, Error>
+    where
+        T: Send + Sync + 'static,
+        E: std::fmt::Display,
+        F: for<'a> Fn(&'a T, &'a slack::Client) -> BoxFuture<'a, Result<(), E>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let schedules = self.schedules.clone();
+        let conn = self.slack();
+        let rng = self.rng.clone();
+        let supervisor = self.supervisor.clone();
+        let reporter = self.reporter.clone();
+        let bot = self.clone();
+        let task = Arc::new((schedule, context, action));
+
+        self.supervisor.spawn(&task.0.name.clone(), move || {
+            let (schedules, conn, rng) = (schedules.clone(), conn.clone(), rng.clone());
+            let (supervisor, reporter, task) = (supervisor.clone(), reporter.clone(), task.clone());
+            let bot = bot.clone();
+
+            async move {
+                let (schedule, context, action) = &*task;
+                let name = &schedule.name;
+
+                loop {
+                    let now = Utc::now();
+                    let next = match schedule.next(schedules.last_run(name), now) {
+                        Some(next) => next,
+                        None => {
+                            info!(%name, "schedule has no more runs");
+                            return;
+                        }
+                    };
+
+                    let jitter = match schedule.jitter.as_millis() as u64 {
+                        0 => Duration::ZERO,
+                        max => Duration::from_millis(rng.with(|r| r.gen_range(0..=max))),
+                    };
+
+                    let delay = (next - now).to_std().unwrap_or(Duration::ZERO) + jitter;
+                    debug!(%name, %next, ?delay, "waiting for next scheduled run");
+                    if !supervisor.sleep(delay).await {
+                        return;
+                    }
+
+                    if bot.is_paused() {
+                        debug!(%name, "paused, skipping scheduled run");
+                    } else {
+                        let res = action(context, &conn).await;
+                        if let Err(error) = res.map_err(|e| format!("{:#}", e)) {
+                            reporter.report(&conn, name, None, &error).await;
+                        }
+                    }
+
+                    // Record the run before jitter, so that jitter doesn't accumulate.
+                    if let Err(error) = schedules.record(name, next.max(now)) {
+                        error!(%error, %name, "failed to record scheduled run");
+                    }
+                }
+            }
+        });
+
+        Ok(self)
+    }
+
+    /// Posts the text produced by `text` to the channel according to the schedule. Nothing is
+    /// posted for runs where `text` returns `None`.
+    pub fn post_on<F>(&self, schedule: Schedule, channel: &str, text: F) -> Result<&Self, Error>
+    where
+        F: Fn() -> Option<String> + Send + Sync + 'static,
+    {
+        self.schedule(
+            schedule,
+            (channel.to_string(), text),
+            |(channel, text), conn| {
+                async move {
+                    match text() {
+                        Some(text) => conn.post(channel, &text, None).await,
+                        None => Ok(()),
+                    }
+                }
+                .boxed()
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn every() {
+        let schedule = Schedule::every("cronk", Duration::from_secs(3600)).unwrap();
+        let now = utc("2022-01-01T12:00:00Z");
+
+        assert_eq!(schedule.next(None, now), Some(utc("2022-01-01T13:00:00Z")));
+
+        let last = utc("2022-01-01T11:30:00Z");
+        assert_eq!(
+            schedule.next(Some(last), now),
+            Some(utc("2022-01-01T12:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn cron_in_time_zone() {
+        let schedule = Schedule::cron("quote", "0 0 9 * * Mon", "America/Chicago").unwrap();
+
+        // Saturday, January 1st 2022.
+        let now = utc("2022-01-01T12:00:00Z");
+        let expected = Tz::America__Chicago
+            .with_ymd_and_hms(2022, 1, 3, 9, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(schedule.next(None, now), Some(expected));
+        assert!(Schedule::cron("bad", "0 0 9 * * Mon", "Far/Far_Away").is_err());
+        assert!(Schedule::cron("bad", "every monday", "UTC").is_err());
+    }
+
+    #[test]
+    fn missed_runs() {
+        let last = utc("2022-01-01T00:00:00Z");
+        let now = utc("2022-01-03T12:00:00Z");
+
+        let skip = Schedule::every("daily", Duration::from_secs(86400)).unwrap();
+        assert_eq!(
+            skip.next(Some(last), now),
+            Some(utc("2022-01-04T12:00:00Z"))
+        );
+
+        let once = skip.missed(Missed::RunOnce);
+        assert_eq!(once.next(Some(last), now), Some(now));
+    }
+}
@@ -0,0 +1,293 @@
+//! Multi-turn conversations. A handler starts a [`Session`] keyed by the user, thread or channel
+//! it wants to hear from next, then awaits further messages from it:
+//!
+//! ```ignore
+//! let mut session = match bot.session(Key::user(msg), 0) {
+//!     Some(s) => s,
+//!     None => return Ok(()), // Already talking to them.
+//! };
+//!
+//! conn.post(&msg.channel, "What is your quest?", None).await?;
+//! while let Some(answer) = session.next(Duration::from_secs(60)).await {
+//!     session.state += 1;
+//!     ...
+//! }
+//! ```
+//!
+//! While a session is active, the messages that continue it are delivered only to the session,
+//! rather than to every handler. Sessions end when they are dropped, so abandoned sessions are
+//! cleaned up as soon as the handler gives up waiting.
+
+use crate::Chatbot;
+use futures::stream::{Stream, StreamExt};
+use slack::{Message, Timestamp};
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Which messages continue a conversation.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// Messages from a user in a channel, in or out of threads.
+    User { channel: String, user: String },
+
+    /// Messages in a thread, from anyone.
+    Thread {
+        channel: String,
+        thread_ts: Timestamp,
+    },
+
+    /// Messages in a channel, from anyone.
+    Channel(String),
+}
+
+impl Key {
+    /// Further messages from the message's author, in the same channel.
+    pub fn user(msg: &Message) -> Self {
+        Key::User {
+            channel: msg.channel.clone(),
+            user: msg.user.clone(),
+        }
+    }
+
+    /// Further messages in the message's thread, which is started by the message if it isn't
+    /// already in one.
+    pub fn thread(msg: &Message) -> Self {
+        Key::Thread {
+            channel: msg.channel.clone(),
+            thread_ts: msg.thread_ts.clone().unwrap_or_else(|| msg.ts.clone()),
+        }
+    }
+
+    /// Further messages in the message's channel.
+    pub fn channel(msg: &Message) -> Self {
+        Key::Channel(msg.channel.clone())
+    }
+
+    pub fn matches(&self, msg: &Message) -> bool {
+        match self {
+            Key::User { channel, user } => &msg.channel == channel && &msg.user == user,
+            Key::Thread { channel, thread_ts } => {
+                &msg.channel == channel && msg.thread_ts.as_ref() == Some(thread_ts)
+            }
+            Key::Channel(channel) => &msg.channel == channel,
+        }
+    }
+}
+
+/// The keys of every active session.
+#[derive(Clone, Default)]
+pub(crate) struct Sessions {
+    active: Arc<Mutex<HashSet<Key>>>,
+}
+
+impl Sessions {
+    pub(crate) fn claims(&self, msg: &Message) -> bool {
+        let active = self.active.lock().unwrap();
+        active.iter().any(|key| key.matches(msg))
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+}
+
+/// An active conversation, with some state of the handler's choosing.
+pub struct Session<S> {
+    pub state: S,
+    key: Key,
+    sessions: Sessions,
+    messages: Pin<Box<dyn Stream<Item = Arc<Message>> + Send>>,
+}
+
+impl<S> Session<S> {
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// Waits for the next message in the conversation. Returns `None` if none arrives within
+    /// `timeout`, or if the bot is stopping.
+    pub async fn next(&mut self, timeout: Duration) -> Option<Arc<Message>> {
+        tokio::time::timeout(timeout, self.messages.next())
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+impl<S> Drop for Session<S> {
+    fn drop(&mut self) {
+        self.sessions.active.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl Chatbot {
+    /// Starts a conversation with whoever sends messages matching `key`. Returns `None` if
+    /// there's already an active session with the same key.
+    pub fn session<S>(&self, key: Key, state: S) -> Option<Session<S>> {
+        if !self.sessions.active.lock().unwrap().insert(key.clone()) {
+            return None;
+        }
+
+        // Sessions hear from the same handle as the handler that started them, so they're
+        // paused and pass through middleware like it.
+        let matching = key.clone();
+        let messages = self
+            .admit(self.delivered().filter(move |msg| {
+                let matches = matching.matches(msg);
+                async move { matches }
+            }))
+            .boxed();
+
+        Some(Session {
+            state,
+            key,
+            sessions: self.sessions.clone(),
+            messages,
+        })
+    }
+
+    /// Whether the message continues an active session.
+    pub fn in_session(&self, msg: &Message) -> bool {
+        self.sessions.claims(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Harness;
+    use crate::Middleware;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn trivia() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+
+        bot.reply("echo", "echo").unwrap();
+        bot.listen(bot.clone(), |bot, conn, msg| {
+            async move {
+                if msg.text != "trivia" {
+                    return Ok(());
+                }
+
+                let mut session = match bot.session(Key::channel(msg), 0) {
+                    Some(s) => s,
+                    None => return Ok(()),
+                };
+
+                conn.post(&msg.channel, "Who lives in a swamp?", None)
+                    .await?;
+
+                while let Some(answer) = session.next(Duration::from_millis(200)).await {
+                    session.state += 1;
+
+                    if answer.text.contains("shrek") {
+                        let text = format!("{} got it in {}", answer.user, session.state);
+                        return conn.post(&msg.channel, &text, None).await;
+                    }
+                }
+
+                conn.post(&msg.channel, "Time's up", None).await
+            }
+            .boxed()
+        })
+        .unwrap();
+
+        harness.say("C1", "U1", "trivia");
+        harness.settle().await;
+        assert!(bot.in_session(&harness.message("C1", "U2", "echo")));
+
+        // Answers go to the session rather than other handlers.
+        harness.say("C1", "U2", "echo");
+        harness.say("C1", "U3", "shrek");
+        harness.settle().await;
+        assert!(!bot.in_session(&harness.message("C1", "U2", "echo")));
+
+        // Sessions time out if nobody answers.
+        harness.say("C1", "U1", "trivia");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        harness.settle().await;
+
+        assert_eq!(
+            harness.posts(),
+            [
+                "Who lives in a swamp?",
+                "U3 got it in 2",
+                "Who lives in a swamp?",
+                "Time's up"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_commands_bypass_sessions() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        bot.admins().add("U1");
+        bot.admin_commands().unwrap();
+
+        let msg = harness.message("C1", "U2", "hi");
+        let _session = bot.session(Key::channel(&msg), ()).unwrap();
+
+        harness.mention("C1", "U1", "pause");
+        harness.settle().await;
+        assert!(bot.is_paused());
+    }
+
+    struct Shout;
+
+    #[async_trait::async_trait]
+    impl Middleware for Shout {
+        async fn inbound(&self, msg: Arc<Message>) -> Option<Arc<Message>> {
+            let mut msg = (*msg).clone();
+            msg.text = msg.text.to_uppercase();
+            Some(Arc::new(msg))
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_follow_pause_and_middleware() {
+        let harness = Harness::new().await;
+        let bot = harness.bot().layer(Shout);
+
+        let msg = harness.message("C1", "U1", "hi");
+        let mut session = bot.session(Key::channel(&msg), ()).unwrap();
+
+        bot.pause();
+        harness.say("C1", "U1", "are you there?");
+        assert!(session.next(Duration::from_millis(100)).await.is_none());
+
+        bot.resume();
+        harness.say("C1", "U1", "hello?");
+        let answer = session.next(Duration::from_millis(100)).await.unwrap();
+        assert_eq!(answer.text, "HELLO?");
+    }
+
+    #[test]
+    fn keys() {
+        let msg = Message {
+            text: "hi".into(),
+            user: "U1".into(),
+            ts: "1.0".into(),
+            thread_ts: None,
+            reply_count: 0,
+            channel: "C1".into(),
+            is_mention: false,
+            annotations: Default::default(),
+        };
+
+        let reply = Message {
+            ts: "2.0".into(),
+            thread_ts: Some("1.0".into()),
+            user: "U2".into(),
+            ..msg.clone()
+        };
+
+        assert!(Key::thread(&msg).matches(&reply));
+        assert!(!Key::user(&msg).matches(&reply));
+        assert!(Key::channel(&msg).matches(&reply));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::middleware::{Middleware, Outbound};
+use tracing::info;
+
+/// Middleware that intercepts everything a handler sends to Slack, logging it instead, so that a
+/// new handler can be tried out on live traffic. Optionally reports what would have been sent to
+/// a debug channel.
+///
+/// ```ignore
+/// bot.layer(Shadow::new().channel("C_DEBUG")).plugin(Gpt2::new(history)).await?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Shadow {
+    channel: Option<String>,
+}
+
+impl Shadow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Posts what would have been sent to the given channel.
+    pub fn channel(mut self, channel: &str) -> Self {
+        self.channel = Some(channel.to_string());
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Shadow {
+    async fn outbound(&self, action: Outbound) -> Option<Outbound> {
+        let report = describe(&action);
+        info!(%report, "shadowed outbound action");
+
+        let debug = self.channel.clone()?;
+
+        // Posts are replaced by reports, so the handler only knows the timestamps of those, and
+        // updates change the report rather than adding another for every streamed update.
+        match action {
+            Outbound::Update { channel, ts, text } => Some(Outbound::Update {
+                channel: debug,
+                ts,
+                text: format!("Would post in <#{}>: {}", channel, text),
+            }),
+            _ => Some(Outbound::Post {
+                channel: debug,
+                text: report,
+                thread_ts: None,
+            }),
+        }
+    }
+}
+
+fn describe(action: &Outbound) -> String {
+    match action {
+        Outbound::Post {
+            channel,
+            text,
+            thread_ts,
+        } => match thread_ts {
+            Some(ts) => format!("Would reply to {} in <#{}>: {}", ts, channel, text),
+            None => format!("Would post in <#{}>: {}", channel, text),
+        },
+        Outbound::Ephemeral {
+            channel,
+            user,
+            text,
+            ..
+        } => format!("Would tell <@{}> in <#{}>: {}", user, channel, text),
+        Outbound::Update { channel, ts, text } => {
+            format!("Would change {} in <#{}> to: {}", ts, channel, text)
+        }
+        Outbound::React { message, emoji } => format!(
+            "Would react with :{}: to {} in <#{}>",
+            emoji, message.ts, message.channel
+        ),
+        Outbound::Upload {
+            parent,
+            filename,
+            content,
+        } => format!(
+            "Would upload {} ({} bytes) in reply to {} in <#{}>",
+            filename,
+            content.len(),
+            parent.ts,
+            parent.channel
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Action, Harness};
+
+    #[tokio::test]
+    async fn intercepts_outbound() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        bot.layer(Shadow::new()).reply("shrek", "SHREK").unwrap();
+        bot.layer(Shadow::new().channel("DEBUG"))
+            .reply("donkey", "DONKEY")
+            .unwrap();
+
+        let msg = harness.say("C1", "U1", "shrek and donkey");
+        harness.settle().await;
+
+        assert_eq!(
+            harness.actions(),
+            [Action::Post {
+                channel: "DEBUG".into(),
+                text: "Would post in <#C1>: DONKEY".into(),
+                thread_ts: None,
+            }]
+        );
+
+        // Actions taken directly on the handle's client are intercepted too.
+        let shadow = bot.layer(Shadow::new());
+        shadow.slack().react(&msg, "onion").await.unwrap();
+        assert!(harness.reactions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn collapses_updates() {
+        let harness = Harness::new().await;
+        let shadow = harness.bot().layer(Shadow::new().channel("DEBUG")).slack();
+
+        let ts = shadow.post_updatable("C1", "Ogres", None).await.unwrap();
+        shadow
+            .update("C1", &ts, "Ogres are like onions")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            harness.actions(),
+            [
+                Action::Post {
+                    channel: "DEBUG".into(),
+                    text: "Would post in <#C1>: Ogres".into(),
+                    thread_ts: None,
+                },
+                Action::Update {
+                    channel: "DEBUG".into(),
+                    ts,
+                    text: "Would post in <#C1>: Ogres are like onions".into(),
+                }
+            ]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use crate::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A JSON file that some piece of state is persisted to.
+pub(crate) struct Store {
+    path: PathBuf,
+    prepared: AtomicU64,
+
+    // The version of the state in the file, held while writing it.
+    written: Mutex<u64>,
+}
+
+/// A write started by [`Store::prepare`].
+pub(crate) struct Write<'a> {
+    store: &'a Store,
+    text: String,
+    version: u64,
+}
+
+impl Store {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            prepared: AtomicU64::new(0),
+            written: Mutex::new(0),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the stored state, or the default if the file doesn't exist yet.
+    pub fn load<T: DeserializeOwned + Default>(&self) -> Result<T, Error> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(text) => Ok(serde_json::from_str(&text)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Serializes the state, so that the caller can release its lock on it before the write is
+    /// finished. Callers prepare while holding that lock, so that writes are versioned in the
+    /// order the state changed.
+    pub fn prepare<T: Serialize>(&self, state: &T) -> Result<Write<'_>, Error> {
+        Ok(Write {
+            store: self,
+            text: serde_json::to_string_pretty(state)?,
+            version: self.prepared.fetch_add(1, Ordering::Relaxed) + 1,
+        })
+    }
+
+    fn tmp_path(&self) -> OsString {
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        tmp
+    }
+}
+
+impl Write<'_> {
+    /// Writes to a temporary file and renames it over the original, so that a crash part way
+    /// through can't leave a truncated file behind. Does nothing if newer state was written
+    /// first.
+    pub fn finish(self) -> Result<(), Error> {
+        let mut written = self.store.written.lock().unwrap();
+        if *written > self.version {
+            return Ok(());
+        }
+
+        let tmp = self.store.tmp_path();
+
+        std::fs::write(&tmp, &self.text)?;
+        std::fs::rename(&tmp, &self.store.path)?;
+
+        *written = self.version;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn round_trips_state() {
+        let path = std::env::temp_dir().join(format!("chatbot-store-{}.json", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let store = Store::new(path.clone());
+
+        let empty: BTreeSet<String> = store.load().unwrap();
+        assert!(empty.is_empty());
+
+        let state: BTreeSet<String> = ["U1".to_string(), "U2".to_string()].into();
+        store.prepare(&state).unwrap().finish().unwrap();
+        assert_eq!(store.load::<BTreeSet<String>>().unwrap(), state);
+        assert!(!Path::new(&store.tmp_path()).exists());
+
+        // Older state never replaces newer state, whichever write finishes first.
+        let old = store.prepare(&BTreeSet::<String>::new()).unwrap();
+        let new = store.prepare(&state).unwrap();
+        new.finish().unwrap();
+        old.finish().unwrap();
+        assert_eq!(store.load::<BTreeSet<String>>().unwrap(), state);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
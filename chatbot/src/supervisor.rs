@@ -0,0 +1,251 @@
+use crate::Chatbot;
+use futures::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{error, info, warn};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+type Tasks = Vec<(String, JoinHandle<()>)>;
+
+/// Tracks the bot's tasks, restarting them with exponential backoff if they panic.
+///
+/// When the bot stops, message streams end, so that handlers stop taking on new messages but
+/// finish the ones they're working on. Tasks should otherwise end promptly once
+/// [`Supervisor::stopped`] resolves, e.g. by sleeping with [`Supervisor::sleep`].
+#[derive(Clone)]
+pub struct Supervisor {
+    stopping: Arc<watch::Sender<bool>>,
+    tasks: Arc<Mutex<Tasks>>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        let (stopping, _) = watch::channel(false);
+
+        Self {
+            stopping: Arc::new(stopping),
+            tasks: Arc::default(),
+        }
+    }
+}
+
+impl Supervisor {
+    /// Spawns the future returned by `task`, calling `task` again to restart it if it panics. The
+    /// first call happens before this returns.
+    pub fn spawn<F, Fut>(&self, name: &str, task: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let first = task();
+        let task_name = name.to_string();
+
+        let handle = tokio::task::spawn(async move {
+            let name = task_name;
+            let mut next = Some(first);
+            let mut backoff = MIN_BACKOFF;
+
+            loop {
+                let started = Instant::now();
+                let fut = next.take().unwrap_or_else(&task);
+
+                match AbortOnDrop(tokio::task::spawn(fut)).await {
+                    Err(err) if err.is_panic() => error!(%name, "task panicked"),
+                    _ => return,
+                }
+
+                if started.elapsed() > MAX_BACKOFF {
+                    backoff = MIN_BACKOFF;
+                }
+
+                warn!(%name, ?backoff, "restarting task");
+                if !supervisor.sleep(backoff).await {
+                    return;
+                }
+
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        });
+
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.push((name.to_string(), handle));
+    }
+
+    /// The number of tasks being supervised.
+    pub fn count(&self) -> usize {
+        self.tasks.lock().unwrap().len()
+    }
+
+    /// Ends message streams and tells tasks to finish up.
+    pub fn stop(&self) {
+        info!("stopping");
+        self.stopping.send_replace(true);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.stopping.borrow()
+    }
+
+    /// Resolves once [`Supervisor::stop`] has been called.
+    pub fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut stopping = self.stopping.subscribe();
+
+        async move {
+            while !*stopping.borrow() {
+                if stopping.changed().await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Sleeps for `duration`, unless the bot stops first. Returns whether the full duration
+    /// elapsed.
+    pub async fn sleep(&self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => true,
+            _ = self.stopped() => false,
+        }
+    }
+
+    /// Waits for every task to finish, aborting any that are still running after `deadline`.
+    /// Returns whether every task finished in time.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        let deadline = tokio::time::Instant::now() + deadline;
+        let mut drained = true;
+
+        info!(count = tasks.len(), "draining tasks");
+
+        for (name, mut handle) in tasks {
+            if tokio::time::timeout_at(deadline, &mut handle)
+                .await
+                .is_err()
+            {
+                warn!(%name, "task didn't finish in time, aborting");
+                handle.abort();
+                drained = false;
+            }
+        }
+
+        drained
+    }
+}
+
+// Aborts the task when dropped, so that aborting a supervising task also aborts the task it
+// supervises.
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Future for AbortOnDrop {
+    type Output = Result<(), JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Chatbot {
+    pub fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
+    /// Stops taking new messages, waits up to `deadline` for handlers to finish the messages
+    /// they're working on, then shuts down plugins and closes the websocket. Returns whether
+    /// every handler finished in time.
+    pub async fn shutdown(&self, deadline: Duration) -> bool {
+        self.supervisor.stop();
+        let drained = self.supervisor.drain(deadline).await;
+
+        self.plugins.shutdown().await;
+        self.slack.close();
+
+        info!(drained, "shut down");
+        drained
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::Harness;
+    use futures::FutureExt;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn drains_in_flight_handlers() {
+        let harness = Harness::new().await;
+        harness
+            .bot()
+            .listen((), |_, conn, msg| {
+                async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    conn.post(&msg.channel, "done", None).await
+                }
+                .boxed()
+            })
+            .unwrap();
+
+        harness.say("C1", "U1", "slow");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(harness.bot().shutdown(Duration::from_secs(5)).await);
+        assert_eq!(harness.posts(), ["done"]);
+
+        // Nothing is handled after shutdown.
+        harness.say("C1", "U1", "slow");
+        harness.settle().await;
+        assert_eq!(harness.posts(), ["done"]);
+    }
+
+    #[tokio::test]
+    async fn aborts_after_deadline() {
+        let harness = Harness::new().await;
+        harness
+            .bot()
+            .listen((), |_, conn, msg| {
+                async move {
+                    tokio::time::sleep(Duration::from_secs(3600)).await;
+                    conn.post(&msg.channel, "done", None).await
+                }
+                .boxed()
+            })
+            .unwrap();
+
+        harness.say("C1", "U1", "slower");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(!harness.bot().shutdown(Duration::from_millis(50)).await);
+        assert!(harness.posts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn restarts_panicked_handlers() {
+        let harness = Harness::new().await;
+        harness
+            .bot()
+            .reply_all(|msg| match msg.text.as_str() {
+                "panic" => panic!("on purpose"),
+                text => Some(text.to_string()),
+            })
+            .unwrap();
+
+        harness.say("C1", "U1", "panic");
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        harness.say("C1", "U1", "still here");
+        harness.settle().await;
+        assert_eq!(harness.posts(), ["still here"]);
+    }
+}
@@ -0,0 +1,401 @@
+//! An offline harness for testing handlers. [`Harness`] runs a [`Chatbot`] over a fake Slack
+//! backend, so that tests can inject messages and make assertions about what the bot did.
+//!
+//! The harness pauses Tokio's clock, so it needs a current thread runtime, as `#[tokio::test]`
+//! gives. Timers only fire once every task is idle, which keeps tests deterministic: sleeping in a
+//! test moves the clock forward by exactly that much.
+//!
+//! ```ignore
+//! let harness = Harness::new().await;
+//! harness.bot().reply_with("echo (.*)", |_, cap| Some(cap[1].to_string()))?;
+//!
+//! harness.say("C1", "U1", "echo hi");
+//! harness.settle().await;
+//!
+//! assert_eq!(harness.posts(), ["hi"]);
+//! ```
+
+use crate::Chatbot;
+use bytes::Bytes;
+use futures::channel::mpsc;
+use slack::{Error, Message, Timestamp};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// The user ID of the bot in the fake workspace.
+pub const BOT_USER_ID: &str = "UBOT";
+
+/// Something the bot did through the Slack API.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Post {
+        channel: String,
+        text: String,
+        thread_ts: Option<Timestamp>,
+    },
+    Ephemeral {
+        channel: String,
+        user: String,
+        text: String,
+        thread_ts: Option<Timestamp>,
+    },
+    Update {
+        channel: String,
+        ts: Timestamp,
+        text: String,
+    },
+    React {
+        channel: String,
+        ts: Timestamp,
+        emoji: String,
+    },
+    Upload {
+        channel: String,
+        thread_ts: Timestamp,
+        filename: String,
+        content: Bytes,
+    },
+}
+
+/// A fake Slack backend that records every action, and serves history, emoji and display names
+/// from memory.
+#[derive(Default)]
+pub struct Fake {
+    actions: Mutex<Vec<Action>>,
+    history: Mutex<Vec<Message>>,
+    emoji: Mutex<Vec<String>>,
+    names: Mutex<HashMap<String, String>>,
+    usergroups: Mutex<HashMap<String, Vec<String>>>,
+    posted: AtomicU64,
+}
+
+impl Fake {
+    pub fn actions(&self) -> Vec<Action> {
+        self.actions.lock().unwrap().clone()
+    }
+
+    pub fn take_actions(&self) -> Vec<Action> {
+        std::mem::take(&mut self.actions.lock().unwrap())
+    }
+
+    /// Adds a message to the history served by `channel_history` and `replies`.
+    pub fn add_history(&self, msg: Message) {
+        self.history.lock().unwrap().push(msg);
+    }
+
+    pub fn add_emoji(&self, emoji: &str) {
+        self.emoji.lock().unwrap().push(emoji.to_string());
+    }
+
+    /// Sets a user's display name. Users without one are displayed as their ID.
+    pub fn set_display_name(&self, user: &str, name: &str) {
+        let mut names = self.names.lock().unwrap();
+        names.insert(user.to_string(), name.to_string());
+    }
+
+    pub fn set_usergroup(&self, usergroup: &str, users: &[&str]) {
+        let mut usergroups = self.usergroups.lock().unwrap();
+        let users = users.iter().map(|u| u.to_string()).collect();
+        usergroups.insert(usergroup.to_string(), users);
+    }
+
+    fn record(&self, action: Action) -> Result<(), Error> {
+        self.actions.lock().unwrap().push(action);
+        Ok(())
+    }
+
+    fn messages(&self, f: impl Fn(&Message) -> bool) -> Vec<Message> {
+        let history = self.history.lock().unwrap();
+        let mut messages: Vec<_> = history.iter().filter(|m| f(m)).cloned().collect();
+
+        messages.sort_by(|a, b| a.ts.cmp(&b.ts));
+        messages
+    }
+}
+
+#[async_trait::async_trait]
+impl slack::Backend for Fake {
+    async fn post(
+        &self,
+        channel: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<(), Error> {
+        self.record(Action::Post {
+            channel: channel.to_string(),
+            text: text.to_string(),
+            thread_ts: parent.cloned(),
+        })
+    }
+
+    async fn post_updatable(
+        &self,
+        channel: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<Timestamp, Error> {
+        self.post(channel, text, parent).await?;
+
+        let posted = self.posted.fetch_add(1, Ordering::SeqCst);
+        Ok(format!("1700000000.{:06}", posted))
+    }
+
+    async fn update(&self, channel: &str, ts: &Timestamp, text: &str) -> Result<(), Error> {
+        self.record(Action::Update {
+            channel: channel.to_string(),
+            ts: ts.clone(),
+            text: text.to_string(),
+        })
+    }
+
+    async fn post_ephemeral(
+        &self,
+        channel: &str,
+        user: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<(), Error> {
+        self.record(Action::Ephemeral {
+            channel: channel.to_string(),
+            user: user.to_string(),
+            text: text.to_string(),
+            thread_ts: parent.cloned(),
+        })
+    }
+
+    async fn emoji_list(&self) -> Result<Vec<String>, Error> {
+        Ok(self.emoji.lock().unwrap().clone())
+    }
+
+    async fn react(&self, message: &Message, emoji: &str) -> Result<(), Error> {
+        self.record(Action::React {
+            channel: message.channel.clone(),
+            ts: message.ts.clone(),
+            emoji: emoji.to_string(),
+        })
+    }
+
+    async fn channel_ids(&self) -> Result<Vec<String>, Error> {
+        let history = self.history.lock().unwrap();
+        let channels: BTreeSet<_> = history.iter().map(|m| m.channel.clone()).collect();
+
+        Ok(channels.into_iter().collect())
+    }
+
+    async fn channel_history(&self, channel_id: &str) -> Result<Vec<Message>, Error> {
+        // Like Slack, return the newest messages first, and only the parents of threads, which
+        // have their own timestamp as their thread timestamp.
+        let mut messages = self.messages(|m| {
+            let top_level = m.thread_ts.iter().all(|ts| ts == &m.ts);
+            m.channel == channel_id && top_level
+        });
+
+        for msg in messages.iter_mut() {
+            let replies =
+                self.messages(|m| m.thread_ts.as_ref() == Some(&msg.ts) && m.ts != msg.ts);
+
+            if !replies.is_empty() {
+                msg.thread_ts = Some(msg.ts.clone());
+                msg.reply_count = replies.len() as u32;
+            }
+        }
+
+        messages.reverse();
+        Ok(messages)
+    }
+
+    async fn replies(&self, channel_id: &str, ts: &str) -> Result<Vec<Message>, Error> {
+        Ok(self.messages(|m| {
+            let in_thread = m.ts == ts || m.thread_ts.as_deref() == Some(ts);
+            m.channel == channel_id && in_thread
+        }))
+    }
+
+    async fn display_name(&self, user_id: &str) -> Result<String, Error> {
+        let names = self.names.lock().unwrap();
+        Ok(names
+            .get(user_id)
+            .cloned()
+            .unwrap_or_else(|| user_id.to_string()))
+    }
+
+    async fn usergroup_members(&self, usergroup_id: &str) -> Result<Vec<String>, Error> {
+        let usergroups = self.usergroups.lock().unwrap();
+        usergroups
+            .get(usergroup_id)
+            .cloned()
+            .ok_or_else(|| Error::Api("no_such_subteam".into()))
+    }
+
+    async fn upload_reply(
+        &self,
+        parent: &Message,
+        filename: &str,
+        content: Bytes,
+    ) -> Result<(), Error> {
+        self.record(Action::Upload {
+            channel: parent.channel.clone(),
+            thread_ts: parent.ts.clone(),
+            filename: filename.to_string(),
+            content,
+        })
+    }
+
+    async fn events(
+        &self,
+        _tx: futures::channel::mpsc::UnboundedSender<Message>,
+        _bot_user_id: &str,
+        mut closed: watch::Receiver<bool>,
+    ) -> Result<(), Error> {
+        // Messages are injected through the harness instead.
+        while !*closed.borrow() {
+            if closed.changed().await.is_err() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A running [`Chatbot`] connected to a [`Fake`] backend. Handlers should be added to
+/// [`Harness::bot`] before any messages are sent.
+pub struct Harness {
+    bot: Chatbot,
+    fake: Arc<Fake>,
+    tx: mpsc::UnboundedSender<Message>,
+    ts: AtomicU64,
+}
+
+impl Harness {
+    /// Creates a harness whose random number generator is seeded with 0.
+    pub async fn new() -> Self {
+        tokio::time::pause();
+
+        let fake = Arc::new(Fake::default());
+        let slack = slack::Client::with_backend(fake.clone(), BOT_USER_ID.into());
+        let bot = Chatbot::new(slack).await.unwrap();
+        bot.rng().reseed(0);
+
+        let (tx, rx) = mpsc::unbounded();
+
+        let runner = bot.clone();
+        tokio::task::spawn(async move { runner.run(rx).await });
+
+        Self {
+            bot,
+            fake,
+            tx,
+            ts: AtomicU64::new(1_600_000_000),
+        }
+    }
+
+    pub fn bot(&self) -> &Chatbot {
+        &self.bot
+    }
+
+    pub fn fake(&self) -> &Fake {
+        &self.fake
+    }
+
+    pub fn seed(&self, seed: u64) {
+        self.bot.rng().reseed(seed);
+    }
+
+    /// Creates a message with a unique timestamp, without sending it.
+    pub fn message(&self, channel: &str, user: &str, text: &str) -> Message {
+        let ts = self.ts.fetch_add(1, Ordering::SeqCst);
+
+        Message {
+            text: text.to_string(),
+            user: user.to_string(),
+            ts: format!("{}.000100", ts),
+            thread_ts: None,
+            reply_count: 0,
+            channel: channel.to_string(),
+            is_mention: false,
+            annotations: Default::default(),
+        }
+    }
+
+    /// Delivers a message to the bot, as if it had arrived over the websocket. Messages sent after
+    /// the bot has shut down are dropped.
+    pub fn send(&self, msg: Message) {
+        self.tx.unbounded_send(msg).ok();
+    }
+
+    /// Sends a top-level message to a channel.
+    pub fn say(&self, channel: &str, user: &str, text: &str) -> Message {
+        let msg = self.message(channel, user, text);
+        self.send(msg.clone());
+        msg
+    }
+
+    /// Sends a message that mentions the bot.
+    pub fn mention(&self, channel: &str, user: &str, text: &str) -> Message {
+        let mut msg = self.message(channel, user, &format!("<@{}> {}", BOT_USER_ID, text));
+        msg.is_mention = true;
+        self.send(msg.clone());
+        msg
+    }
+
+    /// Sends a reply in the thread under `parent`.
+    pub fn reply(&self, parent: &Message, user: &str, text: &str) -> Message {
+        let mut msg = self.message(&parent.channel, user, text);
+        msg.thread_ts = Some(
+            parent
+                .thread_ts
+                .clone()
+                .unwrap_or_else(|| parent.ts.clone()),
+        );
+        self.send(msg.clone());
+        msg
+    }
+
+    /// Waits until the bot has stopped acting on the messages sent so far. Since the clock is
+    /// paused, each sleep here only ends once every task is idle, and anything waiting on a timer
+    /// longer than a millisecond is left waiting.
+    pub async fn settle(&self) {
+        loop {
+            let before = self.fake.actions.lock().unwrap().len();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+
+            if self.fake.actions.lock().unwrap().len() == before {
+                return;
+            }
+        }
+    }
+
+    pub fn actions(&self) -> Vec<Action> {
+        self.fake.actions()
+    }
+
+    pub fn take_actions(&self) -> Vec<Action> {
+        self.fake.take_actions()
+    }
+
+    /// The text of every message posted so far, in order.
+    pub fn posts(&self) -> Vec<String> {
+        self.actions()
+            .into_iter()
+            .filter_map(|a| match a {
+                Action::Post { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The emoji of every reaction added so far, in order.
+    pub fn reactions(&self) -> Vec<String> {
+        self.actions()
+            .into_iter()
+            .filter_map(|a| match a {
+                Action::React { emoji, .. } => Some(emoji),
+                _ => None,
+            })
+            .collect()
+    }
+}
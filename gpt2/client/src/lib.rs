@@ -1,4 +1,10 @@
-pub use gpt2_proto::gpt2::{GenerateRequest, GeneratedText};
+pub use gpt2_proto::gpt2::{
+    Candidate, CountTokensRequest, GenerateRequest, GeneratedChunk, GeneratedText,
+    ModelInfoRequest, StopReason, TokenizeRequest,
+};
 use tonic::transport::Channel;
+pub use tonic::{Code, Status, Streaming};
+pub use tonic_health::proto::{health_check_response::ServingStatus, HealthCheckRequest};
 
 pub type Gpt2Client = gpt2_proto::gpt2::gpt2_client::Gpt2Client<Channel>;
+pub type HealthClient = tonic_health::proto::health_client::HealthClient<Channel>;
@@ -1,3 +1,6 @@
 pub mod gpt2 {
     tonic::include_proto!("gpt2");
 }
+
+/// Descriptors for every service here, for server reflection.
+pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("gpt2_descriptor");
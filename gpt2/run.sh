@@ -15,6 +15,7 @@ docker run -it \
     --init \
     -v "${GPT2_CACHE_DIR}:/cache" \
     -e APP_PORT=50080 \
+    -e GPT2_MODEL=${GPT2_MODEL:-large} \
     -e HSA_ENABLE_SDMA=${HSA_ENABLE_SDMA:-1} \
     --name gpt2_server \
     gpt2_server:latest
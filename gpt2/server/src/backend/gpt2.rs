@@ -0,0 +1,208 @@
+use eyre::{Result, WrapErr};
+use gpt2_proto::gpt2::GenerateRequest;
+use rust_bert::gpt2::GPT2Generator;
+use rust_bert::pipelines::common::{ModelType, TokenizerOption};
+use rust_bert::pipelines::generation_utils::{GenerateConfig, GenerateOptions, LanguageGenerator};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use tch::Tensor;
+use tracing::{debug, info};
+
+use super::{Output, Resources, Stop, TextGenerator, Tokenizer};
+use crate::generator::NUM_BEAMS;
+
+// The most tokens GPT-2 handles at once.
+pub(super) const MAX_SIZE: usize = 1024;
+
+// Every GPT-2 model shares a vocabulary, the last token of which ends the text.
+const VOCAB_SIZE: i64 = 50257;
+const END_OF_TEXT: i64 = VOCAB_SIZE - 1;
+
+impl TextGenerator for GPT2Generator {
+    fn generate(
+        &self,
+        args: &GenerateRequest,
+        prompts: &[&str],
+        length: u32,
+        n: u32,
+        stop: Option<Stop>,
+    ) -> Vec<Output> {
+        let tokenizer = self.get_tokenizer();
+        let prompts: Vec<_> = prompts
+            .iter()
+            .map(|prompt| truncate(tokenizer, prompt, length))
+            .collect();
+
+        // rust-bert left pads shorter prompts in a batch, but doesn't shift their position ids to
+        // match, which throws GPT-2 off. So prompts are only batched with others of the same size.
+        let mut sizes: Vec<usize> = prompts.iter().map(|(_, size)| *size).collect();
+        sizes.sort_unstable();
+        sizes.dedup();
+
+        let mut outputs: Vec<Vec<Output>> = prompts.iter().map(|_| Vec::new()).collect();
+
+        for size in sizes {
+            let group: Vec<usize> = (0..prompts.len())
+                .filter(|&idx| prompts[idx].1 == size)
+                .collect();
+            let texts: Vec<&str> = group.iter().map(|&idx| prompts[idx].0).collect();
+
+            let group_stop = |prompt: usize, text: &str| match stop {
+                Some(stop) => stop(group[prompt], text),
+                None => false,
+            };
+            let group_stop = stop.map(|_| &group_stop as Stop);
+
+            let mut generated =
+                generate_sized(self, args, &texts, size, length, n, group_stop).into_iter();
+
+            for &idx in &group {
+                outputs[idx] = generated.by_ref().take(n as usize).collect();
+            }
+        }
+
+        outputs.into_iter().flatten().collect()
+    }
+
+    fn seed(&self, seed: Option<u64>) {
+        // Every RandomState is keyed from the OS's entropy, so an empty hash is a random number.
+        let seed = seed.unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        tch::manual_seed(seed as i64);
+    }
+}
+
+// Generates for prompts that are all `prompt_size` tokens long.
+fn generate_sized(
+    model: &GPT2Generator,
+    args: &GenerateRequest,
+    prompts: &[&str],
+    prompt_size: usize,
+    length: u32,
+    n: u32,
+    stop: Option<Stop>,
+) -> Vec<Output> {
+    let tokenizer = model.get_tokenizer();
+    let max_length = (prompt_size + length as usize) as i64;
+
+    // rust-bert asks which tokens may come next for each sequence, numbering them by prompt,
+    // unless sampling (the model's default), when each prompt's sequences are numbered
+    // separately. Sequences that should stop may only end the text.
+    let sequences = if args.do_sample.unwrap_or(true) { n } else { 1 };
+    let vocab: Vec<i64> = (0..VOCAB_SIZE).collect();
+    let allowed = |batch_id: i64, ids: &Tensor| {
+        let generated: Vec<i64> = ids.iter::<i64>().unwrap().skip(prompt_size).collect();
+        let text = tokenizer.decode(&generated, true, true);
+
+        match stop {
+            Some(stop) if stop(batch_id as usize / sequences as usize, &text) => {
+                vec![END_OF_TEXT]
+            }
+            _ => vocab.clone(),
+        }
+    };
+
+    let options = GenerateOptions {
+        num_return_sequences: Some(n as i64),
+        output_scores: n > 1,
+        prefix_allowed_tokens_fn: stop.map(|_| &allowed as &dyn Fn(i64, &Tensor) -> Vec<i64>),
+        ..options(args, max_length)
+    };
+
+    model
+        .generate_indices(Some(prompts), Some(options))
+        .into_iter()
+        .map(|output| {
+            let trimmed: Vec<i64> = output.indices.into_iter().skip(prompt_size).collect();
+
+            Output {
+                text: tokenizer.decode(&trimmed, true, true),
+                // Finished sequences are padded with the end of text token.
+                tokens: trimmed.iter().filter(|&&id| id != END_OF_TEXT).count(),
+                score: output.score.map(|s| s as f32),
+            }
+        })
+        .collect()
+}
+
+impl Tokenizer for TokenizerOption {
+    fn tokenize(&self, text: &str) -> Vec<(String, i64)> {
+        let tokens = TokenizerOption::tokenize(self, text);
+        let ids = self.convert_tokens_to_ids(&tokens);
+        tokens.into_iter().zip(ids).collect()
+    }
+}
+
+// Per-request generation options. Unset fields fall back to the model's GenerateConfig.
+fn options(args: &GenerateRequest, max_length: i64) -> GenerateOptions<'static> {
+    GenerateOptions {
+        max_length: Some(max_length),
+        temperature: args.temperature.map(f64::from),
+        top_k: args.top_k.map(i64::from),
+        top_p: args.top_p.map(f64::from),
+        num_beams: args.num_beams.map(i64::from),
+        repetition_penalty: args.repetition_penalty.map(f64::from),
+        no_repeat_ngram_size: args.no_repeat_ngram_size.map(i64::from),
+        do_sample: args.do_sample,
+        ..GenerateOptions::default()
+    }
+}
+
+pub(super) fn load_model(resources: Resources) -> Result<GPT2Generator> {
+    info!(model = %resources.name, "loading gpt2 model");
+    let generator = GPT2Generator::new(GenerateConfig {
+        model_resource: resources.model,
+        config_resource: resources.config,
+        vocab_resource: resources.vocab,
+        merges_resource: resources.merges,
+        max_length: 200,
+        num_beams: NUM_BEAMS as i64,
+        temperature: 1.15,
+        repetition_penalty: 1.0,
+        ..Default::default()
+    })
+    .wrap_err("failed to load gpt2 model")?;
+
+    // TODO: run priming generation
+    info!("gpt2 model loaded");
+
+    Ok(generator)
+}
+
+// A tokenizer separate from the model's, which is only used by the generation loop.
+pub(super) fn load_tokenizer(resources: &Resources) -> Result<TokenizerOption> {
+    let vocab = resources.vocab.get_local_path()?;
+    let merges = resources.merges.get_local_path()?;
+
+    TokenizerOption::from_file(
+        ModelType::GPT2,
+        &vocab.to_string_lossy(),
+        Some(&merges.to_string_lossy()),
+        false,
+        None,
+        None,
+    )
+    .wrap_err("failed to load gpt2 tokenizer")
+}
+
+// Drops the start of the prompt if there isn't room to generate `length` more tokens after it.
+fn truncate<'a>(tokenizer: &TokenizerOption, prompt: &'a str, length: u32) -> (&'a str, usize) {
+    let tokenized = tokenizer.tokenize_with_offsets(prompt);
+    let tok_size = tokenized.tokens.len();
+    let max_size = length as usize + tok_size;
+    let overflow = max_size.saturating_sub(MAX_SIZE);
+
+    let offset = tokenized
+        .offsets
+        .into_iter()
+        .skip(overflow)
+        .find(|o| o.is_some())
+        .flatten()
+        .map(|o| o.begin)
+        .unwrap_or(0) as usize;
+
+    debug!(%offset, "truncating text");
+
+    // The truncated text is tokenized again, since its first token can differ.
+    let truncated = &prompt[offset..];
+    (truncated, tokenizer.tokenize(truncated).len())
+}
@@ -0,0 +1,153 @@
+use gpt2_proto::gpt2::GenerateRequest;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Output, Stop, TextGenerator, Tokenizer};
+
+// Reported like GPT-2's, though the mock doesn't need a limit.
+pub(super) const CONTEXT_SIZE: u32 = 1024;
+
+/// A deterministic stand-in for GPT-2 that needs neither model files nor libtorch, so that the
+/// server and its clients can be run anywhere. It continues each prompt with a bigram model of the
+/// prompt itself, where each token is a word along with the whitespace before it.
+#[derive(Default)]
+pub struct Mock {
+    seed: AtomicU64,
+}
+
+impl TextGenerator for Mock {
+    fn generate(
+        &self,
+        _args: &GenerateRequest,
+        prompts: &[&str],
+        length: u32,
+        n: u32,
+        stop: Option<Stop>,
+    ) -> Vec<Output> {
+        let seed = self.seed.load(Ordering::Relaxed);
+        let stop = stop.unwrap_or(&|_, _| false);
+
+        prompts
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, prompt)| {
+                (0..n).map(move |i| Output {
+                    score: Some(-(i as f32)),
+                    ..continuation(prompt, length, seed + i as u64, |text| stop(idx, text))
+                })
+            })
+            .collect()
+    }
+
+    // Unseeded, the mock goes back to its default seed, since it has no entropy to draw on.
+    fn seed(&self, seed: Option<u64>) {
+        self.seed.store(seed.unwrap_or_default(), Ordering::Relaxed);
+    }
+}
+
+impl Tokenizer for Mock {
+    // Ids are hashes of the tokens, since there's no vocabulary.
+    fn tokenize(&self, text: &str) -> Vec<(String, i64)> {
+        tokens(text)
+            .into_iter()
+            .map(|token| {
+                let mut hasher = DefaultHasher::new();
+                token.hash(&mut hasher);
+                (token.to_string(), (hasher.finish() >> 1) as i64)
+            })
+            .collect()
+    }
+}
+
+// Continues the prompt from its last word. Where a word was followed by several others in the
+// prompt, they're taken in turn, starting from `offset`. The text ends at a word that was never
+// followed by anything, or where `stop` says to.
+fn continuation(prompt: &str, length: u32, offset: u64, stop: impl Fn(&str) -> bool) -> Output {
+    let tokens = tokens(prompt);
+    let mut successors: HashMap<&str, Vec<&str>> = HashMap::new();
+
+    for pair in tokens.windows(2) {
+        successors.entry(pair[0].trim()).or_default().push(pair[1]);
+    }
+
+    let mut text = String::new();
+    let mut generated = 0;
+    let mut word = tokens.last().map_or("", |token| token.trim());
+
+    while generated < length as usize && !stop(&text) {
+        let next = match successors.get(word) {
+            Some(next) => next[(offset as usize + generated) % next.len()],
+            None => break,
+        };
+
+        text.push_str(next);
+        word = next.trim();
+        generated += 1;
+    }
+
+    Output {
+        text,
+        tokens: generated,
+        score: None,
+    }
+}
+
+// Splits text before each run of whitespace.
+fn tokens(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut in_word = false;
+
+    for (idx, c) in text.char_indices() {
+        if c.is_whitespace() && in_word {
+            tokens.push(&text[start..idx]);
+            start = idx;
+        }
+
+        in_word = !c.is_whitespace();
+    }
+
+    if start < text.len() {
+        tokens.push(&text[start..]);
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continues_prompts() {
+        assert_eq!(
+            tokens("Ogres are\n  like onions."),
+            ["Ogres", " are", "\n  like", " onions."]
+        );
+
+        let prompt = "SHREK: Ogres are like onions.\nDONKEY: Onions?\nSHREK: Layers!\nSHREK:";
+        let output = continuation(prompt, 10, 0, |_| false);
+        assert_eq!(
+            output.text,
+            " Ogres are like onions.\nDONKEY: Onions?\nSHREK: Layers!\nSHREK: Layers!"
+        );
+        assert_eq!(output.tokens, 10);
+
+        // Another offset picks another continuation where there's a choice.
+        assert_eq!(
+            continuation(prompt, 3, 1, |_| false).text,
+            " Layers!\nSHREK: Layers!"
+        );
+
+        // Generation ends as soon as it's asked to.
+        let output = continuation(prompt, 10, 0, |text| text.contains('.'));
+        assert_eq!(output.text, " Ogres are like onions.");
+        assert_eq!(output.tokens, 4);
+
+        let output = continuation("the end", 10, 0, |_| false);
+        assert_eq!(output.text, "");
+        assert_eq!(output.tokens, 0);
+    }
+}
@@ -0,0 +1,129 @@
+#[cfg(feature = "torch")]
+mod gpt2;
+mod mock;
+#[cfg(feature = "torch")]
+mod model;
+mod settings;
+
+use eyre::{bail, Result};
+use gpt2_proto::gpt2::GenerateRequest;
+use tracing::info;
+
+pub use mock::Mock;
+#[cfg(feature = "torch")]
+pub use model::Resources;
+pub use settings::Settings;
+
+/// Decides whether a sequence should end where it is, given the index of its prompt and the text
+/// generated for it so far.
+pub type Stop<'a> = &'a dyn Fn(usize, &str) -> bool;
+
+/// A language model that continues text.
+pub trait TextGenerator {
+    /// Continues each prompt by up to `length` tokens, `n` times each, with the request's sampling
+    /// parameters. Returns every continuation of the first prompt, then the second, and so on.
+    /// Prompts too long to leave room for `length` more tokens lose their start. Before each token,
+    /// every sequence is offered to `stop`, and ends there if it returns true.
+    fn generate(
+        &self,
+        args: &GenerateRequest,
+        prompts: &[&str],
+        length: u32,
+        n: u32,
+        stop: Option<Stop>,
+    ) -> Vec<Output>;
+
+    /// Seeds sampling for the generations that follow. With None, sampling is seeded from entropy
+    /// again, as it is to begin with.
+    fn seed(&self, seed: Option<u64>);
+}
+
+/// Splits text into a model's tokens. This is quick, unlike generation, and is done outside of the
+/// generation loop.
+pub trait Tokenizer: Send + Sync {
+    /// The text's tokens, and their ids.
+    fn tokenize(&self, text: &str) -> Vec<(String, i64)>;
+
+    fn count(&self, text: &str) -> usize {
+        self.tokenize(text).len()
+    }
+}
+
+/// A description of the model being served.
+pub struct ModelInfo {
+    pub name: String,
+    pub context_size: u32,
+}
+
+/// A continuation of a prompt.
+pub struct Output {
+    pub text: String,
+    /// How many tokens were generated, which is fewer than asked for if the text ended.
+    pub tokens: usize,
+    /// How likely the model thinks the continuation is, if it was asked for several.
+    pub score: Option<f32>,
+}
+
+/// Which model to generate with. The `backend` setting is either `torch`, to run the GPT-2 model
+/// configured by [`Resources::from_settings`] with rust-bert, or `mock`, to run [`Mock`]. It
+/// defaults to `torch` if the server was built with the torch feature.
+pub enum Backend {
+    #[cfg(feature = "torch")]
+    Torch(Box<Resources>),
+    Mock,
+}
+
+impl Backend {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let default = if cfg!(feature = "torch") {
+            "torch"
+        } else {
+            "mock"
+        };
+
+        let name = settings.backend.as_deref().unwrap_or(default);
+        match name {
+            #[cfg(feature = "torch")]
+            "torch" => Ok(Self::Torch(Box::new(Resources::from_settings(settings)?))),
+            #[cfg(not(feature = "torch"))]
+            "torch" => bail!("gpt2_server was built without the torch feature"),
+            "mock" => Ok(Self::Mock),
+            _ => bail!("unknown backend {:?}, expected torch or mock", name),
+        }
+    }
+
+    pub fn info(&self) -> ModelInfo {
+        match self {
+            #[cfg(feature = "torch")]
+            Self::Torch(resources) => ModelInfo {
+                name: resources.name.clone(),
+                context_size: gpt2::MAX_SIZE as u32,
+            },
+            Self::Mock => ModelInfo {
+                name: "mock".into(),
+                context_size: mock::CONTEXT_SIZE,
+            },
+        }
+    }
+
+    /// Loads the model's tokenizer, downloading its vocabulary if needed.
+    pub fn tokenizer(&self) -> Result<Box<dyn Tokenizer>> {
+        match self {
+            #[cfg(feature = "torch")]
+            Self::Torch(resources) => Ok(Box::new(gpt2::load_tokenizer(resources)?)),
+            Self::Mock => Ok(Box::new(Mock::default())),
+        }
+    }
+
+    /// Loads the model. This can take a while, and should be done in a blocking-safe task.
+    pub fn load(self) -> Result<Box<dyn TextGenerator>> {
+        match self {
+            #[cfg(feature = "torch")]
+            Self::Torch(resources) => Ok(Box::new(gpt2::load_model(*resources)?)),
+            Self::Mock => {
+                info!("using mock model");
+                Ok(Box::new(Mock::default()))
+            }
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use eyre::{bail, Result};
+use rust_bert::gpt2::{
+    Gpt2ConfigResources, Gpt2MergesResources, Gpt2ModelResources, Gpt2VocabResources,
+};
+use rust_bert::resources::{LocalResource, RemoteResource, Resource};
+use std::path::{Path, PathBuf};
+
+use super::Settings;
+
+// The pretrained model to use unless the settings say otherwise.
+const DEFAULT_MODEL: &str = "large";
+
+// The files rust-bert needs to load a model, in the layout of its cache directory.
+const MODEL_FILE: &str = "rust_model.ot";
+const CONFIG_FILE: &str = "config.json";
+const VOCAB_FILE: &str = "vocab.json";
+const MERGES_FILE: &str = "merges.txt";
+
+/// Where to load the model's weights, config and tokenizer from.
+pub struct Resources {
+    pub name: String,
+    pub model: Resource,
+    pub config: Resource,
+    pub vocab: Resource,
+    pub merges: Resource,
+}
+
+impl Resources {
+    /// Picks the model to use. `model_dir` loads a model from a local directory, for hosts without
+    /// internet access. Otherwise `model` picks a pretrained model to download: gpt2, medium, large
+    /// (the default), xl or distilgpt2. Either way, the individual file settings replace the
+    /// matching files, e.g. to try fine-tuned weights with the stock tokenizer.
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let overrides = [
+            (&settings.weights, MODEL_FILE),
+            (&settings.model_config, CONFIG_FILE),
+            (&settings.vocab, VOCAB_FILE),
+            (&settings.merges, MERGES_FILE),
+        ];
+
+        let missing: Vec<_> = overrides
+            .iter()
+            .filter_map(|&(path, _)| path.as_ref())
+            .filter(|path| !path.is_file())
+            .map(|path| path.display().to_string())
+            .collect();
+
+        if !missing.is_empty() {
+            bail!("model files not found: {}", missing.join(", "));
+        }
+
+        let mut resources = match &settings.model_dir {
+            Some(dir) => {
+                // The files that are replaced don't need to be in the directory.
+                let needed: Vec<_> = overrides
+                    .iter()
+                    .filter(|(path, _)| path.is_none())
+                    .map(|(_, file)| *file)
+                    .collect();
+
+                Self::local(dir, &needed)?
+            }
+            None => Self::pretrained(settings.model.as_deref().unwrap_or(DEFAULT_MODEL))?,
+        };
+
+        let replace = |resource: &mut Resource, path: &Option<PathBuf>| {
+            if let Some(path) = path {
+                *resource = Resource::Local(LocalResource {
+                    local_path: path.clone(),
+                });
+            }
+        };
+        replace(&mut resources.model, &settings.weights);
+        replace(&mut resources.config, &settings.model_config);
+        replace(&mut resources.vocab, &settings.vocab);
+        replace(&mut resources.merges, &settings.merges);
+
+        if let Some(weights) = &settings.weights {
+            resources.name = weights.display().to_string();
+        }
+
+        Ok(resources)
+    }
+
+    pub fn pretrained(name: &str) -> Result<Self> {
+        let (model, config, vocab, merges) = match name {
+            "gpt2" => (
+                Gpt2ModelResources::GPT2,
+                Gpt2ConfigResources::GPT2,
+                Gpt2VocabResources::GPT2,
+                Gpt2MergesResources::GPT2,
+            ),
+            "medium" => (
+                Gpt2ModelResources::GPT2_MEDIUM,
+                Gpt2ConfigResources::GPT2_MEDIUM,
+                Gpt2VocabResources::GPT2_MEDIUM,
+                Gpt2MergesResources::GPT2_MEDIUM,
+            ),
+            "large" => (
+                Gpt2ModelResources::GPT2_LARGE,
+                Gpt2ConfigResources::GPT2_LARGE,
+                Gpt2VocabResources::GPT2_LARGE,
+                Gpt2MergesResources::GPT2_LARGE,
+            ),
+            "xl" => (
+                Gpt2ModelResources::GPT2_XL,
+                Gpt2ConfigResources::GPT2_XL,
+                Gpt2VocabResources::GPT2_XL,
+                Gpt2MergesResources::GPT2_XL,
+            ),
+            "distilgpt2" => (
+                Gpt2ModelResources::DISTIL_GPT2,
+                Gpt2ConfigResources::DISTIL_GPT2,
+                Gpt2VocabResources::DISTIL_GPT2,
+                Gpt2MergesResources::DISTIL_GPT2,
+            ),
+            _ => bail!(
+                "unknown model {:?}, expected gpt2, medium, large, xl or distilgpt2",
+                name
+            ),
+        };
+
+        let remote = |resource| Resource::Remote(RemoteResource::from_pretrained(resource));
+
+        Ok(Self {
+            name: name.to_string(),
+            model: remote(model),
+            config: remote(config),
+            vocab: remote(vocab),
+            merges: remote(merges),
+        })
+    }
+
+    /// Loads a model from `dir`, which holds rust_model.ot, config.json, vocab.json and merges.txt
+    /// in rust-bert's layout. Only the `needed` files have to exist.
+    pub fn local(dir: &Path, needed: &[&str]) -> Result<Self> {
+        let missing: Vec<_> = needed
+            .iter()
+            .copied()
+            .filter(|file| !dir.join(file).is_file())
+            .collect();
+
+        if !missing.is_empty() {
+            bail!(
+                "model directory {} is missing {}",
+                dir.display(),
+                missing.join(", ")
+            );
+        }
+
+        let local = |file| {
+            Resource::Local(LocalResource {
+                local_path: dir.join(file),
+            })
+        };
+
+        Ok(Self {
+            name: dir.display().to_string(),
+            model: local(MODEL_FILE),
+            config: local(CONFIG_FILE),
+            vocab: local(VOCAB_FILE),
+            merges: local(MERGES_FILE),
+        })
+    }
+}
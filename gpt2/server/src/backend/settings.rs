@@ -0,0 +1,109 @@
+use eyre::{Result, WrapErr};
+use serde::Deserialize;
+use std::env;
+use std::path::PathBuf;
+
+/// How the model is configured. Settings are read from the TOML file named by
+/// `GPT2_SERVER_CONFIG`, if it's set, and then from the environment, which takes precedence:
+///
+/// | key            | variable            |                                                   |
+/// |----------------|---------------------|---------------------------------------------------|
+/// | `backend`      | `GPT2_BACKEND`      | `torch` or `mock`                                 |
+/// | `model`        | `GPT2_MODEL`        | the pretrained model to download                  |
+/// | `model_dir`    | `GPT2_MODEL_DIR`    | a directory holding all of the model's files      |
+/// | `weights`      | `GPT2_WEIGHTS`      | the model's weights, i.e. rust_model.ot           |
+/// | `model_config` | `GPT2_MODEL_CONFIG` | the model's config.json                           |
+/// | `vocab`        | `GPT2_VOCAB`        | the tokenizer's vocab.json                        |
+/// | `merges`       | `GPT2_MERGES`       | the tokenizer's merges.txt                        |
+///
+/// Each of the file paths replaces just that file of the model otherwise chosen.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub backend: Option<String>,
+    pub model: Option<String>,
+    pub model_dir: Option<PathBuf>,
+    pub weights: Option<PathBuf>,
+    pub model_config: Option<PathBuf>,
+    pub vocab: Option<PathBuf>,
+    pub merges: Option<PathBuf>,
+}
+
+impl Settings {
+    pub fn load() -> Result<Self> {
+        let mut settings = match env::var_os("GPT2_SERVER_CONFIG") {
+            Some(path) => {
+                let text = std::fs::read_to_string(&path)
+                    .wrap_err_with(|| format!("couldn't read GPT2_SERVER_CONFIG {:?}", path))?;
+                Self::parse(&text)
+                    .wrap_err_with(|| format!("invalid GPT2_SERVER_CONFIG {:?}", path))?
+            }
+            None => Self::default(),
+        };
+
+        settings.override_with(|name| env::var(name).ok());
+        Ok(settings)
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    // Replaces the settings that `var` has a value for.
+    fn override_with(&mut self, var: impl Fn(&str) -> Option<String>) {
+        let string = |setting: &mut Option<String>, name| {
+            if let Some(value) = var(name) {
+                *setting = Some(value);
+            }
+        };
+        string(&mut self.backend, "GPT2_BACKEND");
+        string(&mut self.model, "GPT2_MODEL");
+
+        let path = |setting: &mut Option<PathBuf>, name| {
+            if let Some(value) = var(name) {
+                *setting = Some(value.into());
+            }
+        };
+        path(&mut self.model_dir, "GPT2_MODEL_DIR");
+        path(&mut self.weights, "GPT2_WEIGHTS");
+        path(&mut self.model_config, "GPT2_MODEL_CONFIG");
+        path(&mut self.vocab, "GPT2_VOCAB");
+        path(&mut self.merges, "GPT2_MERGES");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_overrides_file() {
+        let mut settings = Settings::parse(
+            r#"
+            model = "medium"
+            model_dir = "/models/gpt2"
+            vocab = "/models/vocab.json"
+            "#,
+        )
+        .unwrap();
+
+        settings.override_with(|name| match name {
+            "GPT2_MODEL" => Some("xl".into()),
+            "GPT2_MERGES" => Some("/models/merges.txt".into()),
+            _ => None,
+        });
+
+        assert_eq!(
+            settings,
+            Settings {
+                model: Some("xl".into()),
+                model_dir: Some("/models/gpt2".into()),
+                vocab: Some("/models/vocab.json".into()),
+                merges: Some("/models/merges.txt".into()),
+                ..Settings::default()
+            }
+        );
+
+        assert!(Settings::parse("modle = \"xl\"").is_err());
+    }
+}
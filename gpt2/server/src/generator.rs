@@ -1,103 +1,656 @@
-use eyre::{Result, WrapErr};
-use gpt2_proto::gpt2::GenerateRequest;
-use rust_bert::gpt2::GPT2Generator;
-use tracing::{debug, info};
+use eyre::Result;
+use gpt2_proto::gpt2::{Candidate, GenerateRequest, GeneratedChunk, GeneratedText, StopReason};
+use regex::Regex;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tonic::Status;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::{debug, info, warn};
 
-use crate::Receiver;
+use crate::backend::{Backend, Output, Stop, TextGenerator};
+use crate::{Generated, Message, Metrics, Receiver, Responder};
 
-const MAX_SIZE: usize = 1024;
+// The default number of beams, unless a request overrides it.
+pub(crate) const NUM_BEAMS: u32 = 5;
 
-// GPT2 generation loop. Enforces that only a single generation is running at once. Not async
+// Generating candidates costs about as much as generating each of them separately.
+const MAX_RETURN_SEQUENCES: u32 = 8;
+
+// How long to wait for more requests to batch with the first, and how many to batch at most.
+const BATCH_WINDOW: Duration = Duration::from_millis(50);
+const MAX_BATCH_SIZE: usize = 8;
+
+// GPT2 generation loop. Enforces that only a single generation is running at once, though
+// compatible requests that arrive close together are batched into one generation. Not async
 // (since rust-bert isn't async), and must be run in a blocking-safe task (e.g.
-// tokio::task::spawn_blocking).
-pub fn gpt2(mut rx: Receiver) -> Result<()> {
-    use rust_bert::pipelines::generation_utils::{GenerateOptions, LanguageGenerator};
+// tokio::task::spawn_blocking) on a tokio runtime.
+pub fn gpt2(
+    mut rx: Receiver,
+    backend: Backend,
+    health: &mut HealthReporter,
+    metrics: &Metrics,
+) -> Result<()> {
+    let generator = backend.load()?;
+    let generator = generator.as_ref();
 
-    let generator = load_model()?;
-    let tokenizer = generator.get_tokenizer();
+    let runtime = Handle::current();
+    runtime.block_on(crate::report(health, ServingStatus::Serving));
+    let mut stats = Stats::default();
 
-    while let Some((args, oneshot_tx)) = rx.blocking_recv() {
-        let (prompt, prompt_size) = truncate(tokenizer, &args);
+    // Requests received while gathering a batch that they can't be part of.
+    let mut deferred = VecDeque::new();
 
-        let max_length = Some((prompt_size + args.length as usize) as i64);
+    while let Some(first) = deferred.pop_front().or_else(|| rx.blocking_recv()) {
+        if metrics.abandoned(&first) {
+            debug!("skipping abandoned request");
+            continue;
+        }
 
-        let mut gen = generator.generate_indices(
-            Some(&[prompt]),
-            Some(GenerateOptions {
-                max_length,
-                ..GenerateOptions::default()
-            }),
-        );
+        if !batchable(&first) {
+            let started = Instant::now();
+            respond(generator, first);
+            stats.record(1, started.elapsed());
+            continue;
+        }
 
-        let trimmed: Vec<i64> = gen
-            .swap_remove(0)
-            .indices
-            .into_iter()
-            .skip(prompt_size)
-            .collect();
+        let mut batch = vec![first];
+        let deadline = tokio::time::Instant::now() + BATCH_WINDOW;
 
-        let output = tokenizer.decode(&trimmed, true, true);
+        while batch.len() < MAX_BATCH_SIZE {
+            match runtime.block_on(tokio::time::timeout_at(deadline, rx.recv())) {
+                Ok(Some(msg)) if batchable(&msg) && compatible(&batch[0].args, &msg.args) => {
+                    batch.push(msg)
+                }
+                Ok(Some(msg)) => deferred.push_back(msg),
+                _ => break,
+            }
+        }
 
-        oneshot_tx.send(output).ok();
+        // Requests can be abandoned while the batch is gathered.
+        batch.retain(|msg| !metrics.abandoned(msg));
+        let size = batch.len();
+        let started = Instant::now();
+
+        if size == 0 {
+            debug!("skipping abandoned batch");
+            continue;
+        } else if size == 1 {
+            respond(generator, batch.swap_remove(0));
+        } else {
+            let (requests, responders): (Vec<_>, Vec<_>) = batch
+                .into_iter()
+                .map(|msg| (msg.args, msg.responder))
+                .unzip();
+
+            for (generated, responder) in generate_batch(generator, &requests)
+                .into_iter()
+                .zip(responders)
+            {
+                if let Responder::Unary(tx) = responder {
+                    tx.send(generated).ok();
+                }
+            }
+        }
+
+        stats.record(size, started.elapsed());
     }
 
     Ok(())
 }
 
-fn load_model() -> Result<GPT2Generator> {
-    use rust_bert::{
-        gpt2::{Gpt2ConfigResources, Gpt2MergesResources, Gpt2ModelResources, Gpt2VocabResources},
-        pipelines::generation_utils::GenerateConfig,
-        resources::{RemoteResource, Resource},
+// Generates text for a single request, sending it to the responder.
+fn respond(generator: &dyn TextGenerator, msg: Message) {
+    let Message {
+        args,
+        responder,
+        deadline,
+    } = msg;
+
+    if let Some(seed) = args.seed {
+        generator.seed(Some(seed));
+    }
+
+    match responder {
+        Responder::Unary(tx) => {
+            tx.send(generate_text(generator, &args)).ok();
+        }
+        Responder::Stream(tx) => {
+            let ending = stream(generator, &args, |chunk| {
+                if matches!(deadline, Some(deadline) if deadline <= tokio::time::Instant::now()) {
+                    let status = Status::deadline_exceeded("generation didn't finish in time");
+                    tx.send(Err(status)).ok();
+                    return false;
+                }
+
+                let chunk = GeneratedChunk {
+                    text: chunk.to_string(),
+                    ..GeneratedChunk::default()
+                };
+
+                tx.send(Ok(chunk)).is_ok()
+            });
+
+            if let Some(Ending { reason, stop }) = ending {
+                let last = GeneratedChunk {
+                    text: String::new(),
+                    stop_reason: Some(reason as i32),
+                    stop,
+                };
+
+                tx.send(Ok(last)).ok();
+            }
+        }
+    }
+
+    // Otherwise every request after this one would be predictable from its seed.
+    if args.seed.is_some() {
+        generator.seed(None);
+    }
+}
+
+// Only unary requests are batched. Streams are sent as they're generated, and seeds need the
+// request to be generated alone.
+fn batchable(
+    Message {
+        args, responder, ..
+    }: &Message,
+) -> bool {
+    matches!(responder, Responder::Unary(_)) && args.seed.is_none()
+}
+
+// Requests in a batch share one set of generation options, though each has its own stops.
+fn compatible(a: &GenerateRequest, b: &GenerateRequest) -> bool {
+    let params = |args: &GenerateRequest| {
+        (
+            args.length,
+            args.temperature,
+            args.top_k,
+            args.top_p,
+            args.num_beams,
+            args.repetition_penalty,
+            args.no_repeat_ngram_size,
+            args.do_sample,
+            args.num_return_sequences,
+        )
     };
 
-    info!("loading gpt2 model");
-    let generator = GPT2Generator::new(GenerateConfig {
-        model_resource: Resource::Remote(RemoteResource::from_pretrained(
-            Gpt2ModelResources::GPT2_LARGE,
-        )),
-        config_resource: Resource::Remote(RemoteResource::from_pretrained(
-            Gpt2ConfigResources::GPT2_LARGE,
-        )),
-        vocab_resource: Resource::Remote(RemoteResource::from_pretrained(
-            Gpt2VocabResources::GPT2_LARGE,
-        )),
-        merges_resource: Resource::Remote(RemoteResource::from_pretrained(
-            Gpt2MergesResources::GPT2_LARGE,
-        )),
-        max_length: 200,
-        num_beams: 5,
-        temperature: 1.15,
-        repetition_penalty: 1.0,
-        ..Default::default()
+    params(a) == params(b)
+}
+
+// Generates every candidate the request asks for, best first.
+fn generate_text(generator: &dyn TextGenerator, args: &GenerateRequest) -> Generated {
+    generate_batch(generator, std::slice::from_ref(args)).swap_remove(0)
+}
+
+// Generates text for several compatible requests with a single call to the model. Each candidate
+// ends as soon as it contains one of its request's stops.
+fn generate_batch(generator: &dyn TextGenerator, batch: &[GenerateRequest]) -> Vec<Generated> {
+    let first = &batch[0];
+    let n = first.num_return_sequences.unwrap_or(1);
+
+    let prompts: Vec<&str> = batch.iter().map(|args| args.prompt.as_str()).collect();
+    let stops: Vec<Stops> = batch.iter().map(Stops::new).collect();
+    let stop = |prompt: usize, text: &str| stops[prompt].find(text).is_some();
+    let stop = match stops.iter().all(Stops::is_empty) {
+        true => None,
+        false => Some(&stop as Stop),
+    };
+
+    let mut outputs = generator
+        .generate(first, &prompts, first.length, n, stop)
+        .into_iter();
+
+    stops
+        .iter()
+        .map(|stops| {
+            let candidates = outputs
+                .by_ref()
+                .take(n as usize)
+                .map(|output| candidate(stops, first.length, output))
+                .collect();
+
+            ranked(candidates)
+        })
+        .collect()
+}
+
+// Generation throughput since the server started.
+#[derive(Default)]
+struct Stats {
+    batches: u64,
+    requests: u64,
+    busy: Duration,
+}
+
+impl Stats {
+    fn record(&mut self, size: usize, elapsed: Duration) {
+        self.batches += 1;
+        self.requests += size as u64;
+        self.busy += elapsed;
+
+        info!(
+            size,
+            elapsed_ms = elapsed.as_millis() as u64,
+            requests = self.requests,
+            mean_batch_size = self.requests as f64 / self.batches as f64,
+            requests_per_sec = self.requests as f64 / self.busy.as_secs_f64(),
+            "generation finished"
+        );
+    }
+}
+
+// Sorts candidates best first, making the best the generated text.
+fn ranked(mut candidates: Vec<Candidate>) -> Generated {
+    candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    let best = match candidates.first() {
+        Some(best) => best.clone(),
+        None => return Err(Status::internal("the model generated no text")),
+    };
+
+    Ok(GeneratedText {
+        text: best.text,
+        stop_reason: best.stop_reason,
+        stop: best.stop,
+        candidates,
     })
-    .wrap_err("failed to load gpt2 model")?;
+}
+
+fn candidate(stops: &Stops, length: u32, mut output: Output) -> Candidate {
+    let Ending { reason, stop } = ending(stops, length, &mut output);
+
+    Candidate {
+        text: output.text,
+        score: output.score,
+        stop_reason: reason as i32,
+        stop,
+    }
+}
+
+// How generation ended.
+struct Ending {
+    reason: StopReason,
+    stop: String,
+}
+
+// Works out why the output ended, truncating it at the first stop in it.
+fn ending(stops: &Stops, length: u32, output: &mut Output) -> Ending {
+    let (reason, stop) = match stops.find(&output.text) {
+        Some((offset, stop)) => {
+            debug!(%stop, "stop sequence generated");
+            let stop = stop.to_string();
+            output.text.truncate(offset);
+            (StopReason::StopSequence, stop)
+        }
+        None if output.tokens < length as usize => (StopReason::EndOfText, String::new()),
+        None => (StopReason::Length, String::new()),
+    };
+
+    Ending { reason, stop }
+}
+
+// Generates a single sequence for the request, passing its text to `emit` in chunks as it's
+// generated. Returns None if `emit` returns false, i.e. nobody is listening any more.
+fn stream(
+    generator: &dyn TextGenerator,
+    args: &GenerateRequest,
+    emit: impl FnMut(&str) -> bool,
+) -> Option<Ending> {
+    let stops = Stops::new(args);
+    let emit = RefCell::new(emit);
+    let sent = RefCell::new(String::new());
+    let listening = Cell::new(true);
+
+    // Sends whatever can't turn out to be part of a stop, ending generation once a stop has been
+    // generated or nobody is listening. A character split across tokens decodes as U+FFFD until
+    // the rest of it has been generated, so that's held back too, and text that no longer starts
+    // with what was sent waits until it does again.
+    let step = |_: usize, text: &str| {
+        if stops.find(text).is_some() {
+            return true;
+        }
+
+        let mut sent = sent.borrow_mut();
+        let text = text.trim_end_matches(char::REPLACEMENT_CHARACTER);
+        let end = stops.safe_end(text);
+        if end > sent.len() && text.starts_with(sent.as_str()) {
+            let chunk = &text[sent.len()..end];
+            if !(emit.borrow_mut())(chunk) {
+                listening.set(false);
+                return true;
+            }
+
+            sent.push_str(chunk);
+        }
+
+        false
+    };
+
+    // Beams are ranked once they're all finished, so there'd be nothing to send until then.
+    let args = GenerateRequest {
+        num_beams: Some(1),
+        ..args.clone()
+    };
+
+    let mut output = generator
+        .generate(&args, &[&args.prompt], args.length, 1, Some(&step))
+        .swap_remove(0);
+
+    if !listening.get() {
+        return None;
+    }
+
+    let ending = ending(&stops, args.length, &mut output);
+    let sent = sent.into_inner();
+    match output.text.strip_prefix(sent.as_str()) {
+        Some(rest) if !rest.is_empty() => {
+            if !(emit.borrow_mut())(rest) {
+                return None;
+            }
+        }
+        Some(_) => {}
+        None => warn!(%sent, text = %output.text, "generated text doesn't continue what was sent"),
+    }
+
+    Some(ending)
+}
+
+// A request's stop strings and regexes.
+struct Stops<'a> {
+    strings: &'a [String],
+    regexes: Vec<Regex>,
+}
+
+impl<'a> Stops<'a> {
+    // Regexes are checked by validate, so any that don't compile are ignored.
+    fn new(args: &'a GenerateRequest) -> Self {
+        Self {
+            strings: &args.stop,
+            regexes: args
+                .stop_regex
+                .iter()
+                .filter_map(|re| Regex::new(re).ok())
+                .collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.strings.is_empty() && self.regexes.is_empty()
+    }
+
+    // How much of the text can be sent without risking sending part of a stop sequence that
+    // hasn't been completely generated yet. Regexes can match anything, so when there are any, the
+    // last word is held back, on the assumption that stops start at word boundaries.
+    fn safe_end(&self, text: &str) -> usize {
+        let longest = self.strings.iter().map(|s| s.len()).max().unwrap_or(0);
+        let mut end = text.len().saturating_sub(longest.saturating_sub(1));
+
+        if !self.regexes.is_empty() {
+            let last_word = text.rfind(char::is_whitespace).unwrap_or(0);
+            end = end.min(last_word);
+        }
+
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        end
+    }
+
+    // The earliest stop in the text, and where it starts.
+    fn find(&self, text: &str) -> Option<(usize, &str)> {
+        let strings = self
+            .strings
+            .iter()
+            .filter_map(|s| text.find(s.as_str()).map(|idx| (idx, s.as_str())));
+
+        let regexes = self
+            .regexes
+            .iter()
+            .filter_map(|re| re.find(text).map(|m| (m.start(), re.as_str())));
+
+        strings.chain(regexes).min_by_key(|(idx, _)| *idx)
+    }
+}
 
-    // TODO: run priming generation
-    info!("gpt2 model loaded");
+/// Checks that the request's sampling parameters are in range and its stop regexes compile,
+/// describing the first problem found.
+pub fn validate(args: &GenerateRequest) -> Result<(), String> {
+    if matches!(args.temperature, Some(t) if t <= 0.0) {
+        return Err("temperature must be positive".into());
+    }
+
+    if matches!(args.top_p, Some(p) if p <= 0.0 || p > 1.0) {
+        return Err("top_p must be in (0, 1]".into());
+    }
+
+    if args.num_beams == Some(0) {
+        return Err("num_beams must be at least 1".into());
+    }
+
+    match args.num_return_sequences {
+        Some(0) => return Err("num_return_sequences must be at least 1".into()),
+        Some(n) if n > MAX_RETURN_SEQUENCES => {
+            return Err(format!(
+                "num_return_sequences must be at most {}",
+                MAX_RETURN_SEQUENCES
+            ))
+        }
+        Some(n) if args.do_sample != Some(true) && n > args.num_beams.unwrap_or(NUM_BEAMS) => {
+            return Err("num_return_sequences must be at most num_beams without sampling".into())
+        }
+        _ => {}
+    }
+
+    if matches!(args.repetition_penalty, Some(p) if p < 1.0) {
+        return Err("repetition_penalty must be at least 1".into());
+    }
 
-    Ok(generator)
+    if args.stop.iter().any(|s| s.is_empty()) {
+        return Err("stop strings must not be empty".into());
+    }
+
+    for re in &args.stop_regex {
+        Regex::new(re).map_err(|err| format!("invalid stop_regex: {}", err))?;
+    }
+
+    Ok(())
 }
 
-fn truncate<'a>(
-    tokenizer: &rust_bert::pipelines::common::TokenizerOption,
-    args: &'a GenerateRequest,
-) -> (&'a str, usize) {
-    let tokenized = tokenizer.tokenize_with_offsets(&args.prompt);
-    let tok_size = tokenized.tokens.len();
-    let max_size = args.length as usize + tok_size;
-    let overflow = max_size.saturating_sub(MAX_SIZE);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Mock;
+
+    const SCRIPT: &str =
+        "SHREK: Ogres are like onions.\nDONKEY: They stink?\nSHREK: Yes. No!\nSHREK:";
+
+    fn request(length: u32) -> GenerateRequest {
+        GenerateRequest {
+            prompt: SCRIPT.into(),
+            length,
+            ..GenerateRequest::default()
+        }
+    }
 
-    let offset = tokenized
-        .offsets
-        .into_iter()
-        .skip(overflow)
-        .find(|o| o.is_some())
-        .flatten()
-        .map(|o| o.begin)
-        .unwrap_or(0) as usize;
+    #[test]
+    fn stops_at_stop_sequences() {
+        let args = GenerateRequest {
+            stop: vec!["\nDONKEY:".into()],
+            ..request(20)
+        };
 
-    debug!(%offset, "truncating text");
+        let generated = generate_text(&Mock::default(), &args).unwrap();
+        assert_eq!(generated.text, " Ogres are like onions.");
+        assert_eq!(generated.stop_reason(), StopReason::StopSequence);
+        assert_eq!(generated.stop, "\nDONKEY:");
 
-    (&args.prompt[offset..], tok_size - overflow)
+        // Streamed chunks never include the start of the stop sequence.
+        let mut chunks = Vec::new();
+        stream(&Mock::default(), &args, |chunk| {
+            chunks.push(chunk.to_string());
+            true
+        });
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), generated.text);
+
+        let generated = generate_text(&Mock::default(), &request(3)).unwrap();
+        assert_eq!(generated.text, " Ogres are like");
+        assert_eq!(generated.stop_reason(), StopReason::Length);
+    }
+
+    #[test]
+    fn stops_match_unstopped_output() {
+        let mut args = GenerateRequest {
+            num_return_sequences: Some(2),
+            ..request(20)
+        };
+        let unstopped = generate_text(&Mock::default(), &args).unwrap();
+
+        args.stop_regex = vec![r"[.!]".into()];
+        let stopped = generate_text(&Mock::default(), &args).unwrap();
+
+        for (stopped, unstopped) in stopped.candidates.iter().zip(&unstopped.candidates) {
+            let offset = unstopped.text.find(['.', '!']).unwrap();
+            assert_eq!(stopped.text, unstopped.text[..offset]);
+            assert_eq!(stopped.stop_reason(), StopReason::StopSequence);
+        }
+    }
+
+    #[test]
+    fn ranks_candidates() {
+        let args = GenerateRequest {
+            num_return_sequences: Some(2),
+            ..request(2)
+        };
+
+        let generated = generate_text(&Mock::default(), &args).unwrap();
+        let candidates: Vec<_> = generated
+            .candidates
+            .iter()
+            .map(|c| c.text.as_str())
+            .collect();
+        assert_eq!(candidates, [" Ogres are", " Yes. No!"]);
+        assert_eq!(generated.text, " Ogres are");
+
+        assert!(ranked(vec![]).is_err());
+    }
+
+    #[test]
+    fn batches_requests() {
+        let batch = [
+            request(2),
+            GenerateRequest {
+                prompt: "the end".into(),
+                ..request(2)
+            },
+        ];
+
+        let generated: Vec<_> = generate_batch(&Mock::default(), &batch)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(generated[0].text, " Ogres are");
+        assert_eq!(generated[1].text, "");
+        assert_eq!(generated[1].stop_reason(), StopReason::EndOfText);
+
+        // Each request in a batch has its own stops.
+        let batch = [
+            GenerateRequest {
+                stop: vec![" like".into()],
+                ..request(20)
+            },
+            GenerateRequest {
+                stop: vec!["?".into()],
+                ..request(20)
+            },
+        ];
+
+        let generated: Vec<_> = generate_batch(&Mock::default(), &batch)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(generated[0].text, " Ogres are");
+        assert_eq!(
+            generated[1].text,
+            " Ogres are like onions.\nDONKEY: They stink"
+        );
+    }
+
+    // Decodes like GPT-2's byte-level tokenizer, where a character split across tokens is U+FFFD
+    // until its last byte has been generated.
+    struct Rewriting(&'static [&'static str]);
+
+    impl TextGenerator for Rewriting {
+        fn generate(
+            &self,
+            _: &GenerateRequest,
+            _: &[&str],
+            _: u32,
+            _: u32,
+            stop: Option<Stop>,
+        ) -> Vec<Output> {
+            let mut tokens = 0;
+            for text in self.0 {
+                if matches!(stop, Some(stop) if stop(0, text)) {
+                    break;
+                }
+                tokens += 1;
+            }
+
+            vec![Output {
+                text: self.0[tokens.max(1) - 1].into(),
+                tokens,
+                score: None,
+            }]
+        }
+
+        fn seed(&self, _: Option<u64>) {}
+    }
+
+    #[test]
+    fn streams_whole_characters() {
+        let generator = Rewriting(&[
+            " Ogres",
+            " Ogres \u{FFFD}",
+            " Ogres \u{1F9C5}",
+            " Ogres \u{1F9C5} are",
+            " Ogres \u{1F9C5} are like onions.",
+        ]);
+
+        let mut chunks = Vec::new();
+        stream(&generator, &request(20), |chunk| {
+            chunks.push(chunk.to_string());
+            true
+        });
+        assert_eq!(chunks.concat(), " Ogres \u{1F9C5} are like onions.");
+        assert!(!chunks.concat().contains(char::REPLACEMENT_CHARACTER));
+    }
+
+    #[test]
+    fn seeds_only_their_own_request() {
+        let generator = Mock::default();
+        let unary = |args| {
+            let (tx, mut rx) = tokio::sync::oneshot::channel();
+            let msg = Message {
+                args,
+                responder: Responder::Unary(tx),
+                deadline: None,
+            };
+
+            respond(&generator, msg);
+            rx.try_recv().unwrap().unwrap().text
+        };
+
+        let unseeded = unary(request(5));
+        let seeded = unary(GenerateRequest {
+            seed: Some(1),
+            ..request(5)
+        });
+        assert_ne!(seeded, unseeded);
+        assert_eq!(unary(request(5)), unseeded);
+    }
 }
@@ -1,23 +1,122 @@
+mod backend;
 mod generator;
 
 use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
-    sync::{mpsc, oneshot},
+    sync::{mpsc, mpsc::error::TrySendError, oneshot},
     task,
+    time::Instant,
 };
-use tonic::{transport::Server, Request, Response, Status};
-use tracing::{debug, error, info};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::transport::{NamedService, Server};
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, info, warn};
 
 use gpt2_proto::gpt2::gpt2_server as proto;
-use gpt2_proto::gpt2::{GenerateRequest, GeneratedText};
+use gpt2_proto::gpt2::{
+    CountTokensRequest, CountTokensResponse, GenerateRequest, GeneratedChunk, GeneratedText,
+    ModelInfoRequest, ModelInfoResponse, TokenizeRequest, TokenizeResponse,
+};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+
+type Sender = mpsc::Sender<Message>;
+type Receiver = mpsc::Receiver<Message>;
+type ChunkSender = mpsc::UnboundedSender<Result<GeneratedChunk, Status>>;
+type Generated = Result<GeneratedText, Status>;
+
+// How many requests can wait for generation before new ones are turned away.
+const QUEUE_SIZE: usize = 32;
+
+// A request waiting for the generation loop.
+struct Message {
+    args: GenerateRequest,
+    responder: Responder,
+    deadline: Option<Instant>,
+}
+
+impl Message {
+    // Whether nobody is waiting for the result any more, because the client went away or its
+    // deadline passed.
+    fn abandoned(&self) -> bool {
+        let closed = match &self.responder {
+            Responder::Unary(tx) => tx.is_closed(),
+            Responder::Stream(tx) => tx.is_closed(),
+        };
+
+        closed || self.expired()
+    }
 
-type Responder = oneshot::Sender<String>;
-type Message = (GenerateRequest, Responder);
-type Sender = mpsc::UnboundedSender<Message>;
-type Receiver = mpsc::UnboundedReceiver<Message>;
+    fn expired(&self) -> bool {
+        matches!(self.deadline, Some(deadline) if deadline <= Instant::now())
+    }
+}
+
+// Counts of requests that never reached the model.
+#[derive(Default)]
+struct Metrics {
+    rejected: AtomicU64,
+    expired: AtomicU64,
+    cancelled: AtomicU64,
+}
+
+impl Metrics {
+    // Whether the request was abandoned, counting it if so.
+    fn abandoned(&self, msg: &Message) -> bool {
+        let counter = if msg.expired() {
+            &self.expired
+        } else if msg.abandoned() {
+            &self.cancelled
+        } else {
+            return false;
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+// Where the generation loop sends the result of a request.
+enum Responder {
+    Unary(oneshot::Sender<Generated>),
+    Stream(ChunkSender),
+}
 
 struct Gpt2 {
     generator_tx: Sender,
+    tokenizer: Box<dyn backend::Tokenizer>,
+    info: backend::ModelInfo,
+    metrics: Arc<Metrics>,
+}
+
+impl Gpt2 {
+    // How many requests are waiting for generation.
+    fn queue_depth(&self) -> usize {
+        QUEUE_SIZE - self.generator_tx.capacity()
+    }
+
+    // Queues the request for generation, unless the queue is full.
+    fn enqueue(&self, message: Message) -> Result<(), Status> {
+        self.generator_tx
+            .try_send(message)
+            .map_err(|err| match err {
+                TrySendError::Full(_) => {
+                    self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        queue_depth = QUEUE_SIZE,
+                        "generation queue full, rejecting request"
+                    );
+                    Status::resource_exhausted("too many generation requests are waiting")
+                }
+                TrySendError::Closed(_) => {
+                    error!("failed to send generation request, processing loop has stopped");
+                    Status::internal("failed to contact internal processing loop")
+                }
+            })
+    }
 }
 
 #[tonic::async_trait]
@@ -26,27 +125,140 @@ impl proto::Gpt2 for Gpt2 {
         &self,
         request: Request<GenerateRequest>,
     ) -> Result<Response<GeneratedText>, Status> {
-        let gen_tx = self.generator_tx.clone();
-        let (tx, rx) = oneshot::channel::<String>();
+        let (tx, rx) = oneshot::channel::<Generated>();
 
+        let deadline = deadline(&request);
         let payload = request.into_inner();
 
-        info!(length=%payload.length, prompt=%payload.prompt, "new generation request");
+        info!(length=%payload.length, prompt=%payload.prompt, queue_depth=%self.queue_depth(), "new generation request");
+
+        generator::validate(&payload).map_err(Status::invalid_argument)?;
 
-        gen_tx.send((payload, tx)).map_err(|err| {
-            error!(%err, "failed to send generation request");
-            Status::internal("failed to contact internal processing loop")
+        self.enqueue(Message {
+            args: payload,
+            responder: Responder::Unary(tx),
+            deadline,
         })?;
 
-        let text: String = rx.await.map_err(|err| {
-            error!(%err, "failed to receive generation response");
-            Status::internal("internal processing loop failed to reply")
+        let reply = async {
+            rx.await.map_err(|err| {
+                error!(%err, "failed to receive generation response");
+                Status::internal("internal processing loop failed to reply")
+            })?
+        };
+
+        let generated = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, reply)
+                .await
+                .map_err(|_| Status::deadline_exceeded("generation didn't finish in time"))??,
+            None => reply.await?,
+        };
+
+        info!(text=%generated.text, stop_reason=?generated.stop_reason(), "gpt2 text generated");
+
+        Ok(Response::new(generated))
+    }
+
+    type GenerateTextStreamStream = UnboundedReceiverStream<Result<GeneratedChunk, Status>>;
+
+    async fn generate_text_stream(
+        &self,
+        request: Request<GenerateRequest>,
+    ) -> Result<Response<Self::GenerateTextStreamStream>, Status> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let deadline = deadline(&request);
+        let payload = request.into_inner();
+
+        info!(length=%payload.length, prompt=%payload.prompt, queue_depth=%self.queue_depth(), "new streaming generation request");
+
+        generator::validate(&payload).map_err(Status::invalid_argument)?;
+        if matches!(payload.num_return_sequences, Some(n) if n > 1) {
+            let msg = "streaming only supports a single sequence";
+            return Err(Status::invalid_argument(msg));
+        }
+        if matches!(payload.num_beams, Some(n) if n > 1) {
+            let msg = "streaming doesn't support beam search";
+            return Err(Status::invalid_argument(msg));
+        }
+
+        self.enqueue(Message {
+            args: payload,
+            responder: Responder::Stream(tx),
+            deadline,
         })?;
 
-        info!(%text, "gpt2 text generated");
+        Ok(Response::new(UnboundedReceiverStream::new(rx)))
+    }
+
+    async fn tokenize(
+        &self,
+        request: Request<TokenizeRequest>,
+    ) -> Result<Response<TokenizeResponse>, Status> {
+        let text = request.into_inner().text;
+        let (tokens, ids) = self.tokenizer.tokenize(&text).into_iter().unzip();
+
+        Ok(Response::new(TokenizeResponse { tokens, ids }))
+    }
+
+    async fn count_tokens(
+        &self,
+        request: Request<CountTokensRequest>,
+    ) -> Result<Response<CountTokensResponse>, Status> {
+        let texts = request.into_inner().texts;
+        let counts = texts
+            .iter()
+            .map(|text| self.tokenizer.count(text) as u32)
+            .collect();
 
-        Ok(Response::new(GeneratedText { text }))
+        Ok(Response::new(CountTokensResponse { counts }))
     }
+
+    async fn model_info(
+        &self,
+        _: Request<ModelInfoRequest>,
+    ) -> Result<Response<ModelInfoResponse>, Status> {
+        let metrics = &self.metrics;
+
+        Ok(Response::new(ModelInfoResponse {
+            name: self.info.name.clone(),
+            context_size: self.info.context_size,
+            queue_depth: self.queue_depth() as u32,
+            queue_size: QUEUE_SIZE as u32,
+            rejected: metrics.rejected.load(Ordering::Relaxed),
+            expired: metrics.expired.load(Ordering::Relaxed),
+            cancelled: metrics.cancelled.load(Ordering::Relaxed),
+        }))
+    }
+}
+
+// When the client stops waiting for the request, from its `grpc-timeout` header. Tonic cancels
+// unary handlers once this passes, but the generation loop and streams need to know it too.
+fn deadline<T>(request: &Request<T>) -> Option<Instant> {
+    let header = request.metadata().get("grpc-timeout")?.to_str().ok()?;
+    let (value, unit) = header.split_at(header.len().checked_sub(1)?);
+    let value: u64 = value.parse().ok()?;
+
+    let timeout = match unit {
+        "H" => Duration::from_secs(value * 60 * 60),
+        "M" => Duration::from_secs(value * 60),
+        "S" => Duration::from_secs(value),
+        "m" => Duration::from_millis(value),
+        "u" => Duration::from_micros(value),
+        "n" => Duration::from_nanos(value),
+        _ => return None,
+    };
+
+    Some(Instant::now() + timeout)
+}
+
+// Reports the health of the server as a whole and of the gpt2 service, which are the same: nothing
+// is served until the model has loaded, or once the generation loop has stopped.
+async fn report(reporter: &mut HealthReporter, status: ServingStatus) {
+    reporter.set_service_status("", status).await;
+    reporter
+        .set_service_status(<proto::Gpt2Server<Gpt2> as NamedService>::NAME, status)
+        .await;
 }
 
 #[tokio::main]
@@ -60,14 +272,68 @@ async fn main() {
         )
         .init();
 
-    let (tx, rx) = mpsc::unbounded_channel::<Message>();
-    task::spawn_blocking(move || {
+    let backend = match backend::Settings::load()
+        .and_then(|settings| backend::Backend::from_settings(&settings))
+    {
+        Ok(backend) => backend,
+        Err(err) => {
+            error!(?err, "invalid gpt2 model configuration");
+            std::process::exit(1);
+        }
+    };
+
+    let tokenizer = match backend.tokenizer() {
+        Ok(tokenizer) => tokenizer,
+        Err(err) => {
+            error!(?err, "failed to load gpt2 tokenizer");
+            std::process::exit(1);
+        }
+    };
+    let info = backend.info();
+
+    let (mut reporter, health) = tonic_health::server::health_reporter();
+    report(&mut reporter, ServingStatus::NotServing).await;
+    let metrics = Arc::new(Metrics::default());
+    let (tx, rx) = mpsc::channel::<Message>(QUEUE_SIZE);
+
+    let mut loop_reporter = reporter.clone();
+    let loop_metrics = metrics.clone();
+    let generation = task::spawn_blocking(move || {
         debug!("starting gpt2 loop");
-        let res = generator::gpt2(rx);
-        debug!(?res, "ending gpt2 loop");
+        generator::gpt2(rx, backend, &mut loop_reporter, &loop_metrics)
     });
 
-    let gpt2 = Gpt2 { generator_tx: tx };
+    // Without the generation loop, every request fails, whether it ended or panicked.
+    tokio::spawn(async move {
+        match generation.await {
+            Ok(Ok(())) => debug!("gpt2 loop ended"),
+            Ok(Err(err)) => error!(?err, "gpt2 loop failed"),
+            Err(err) => error!(%err, "gpt2 loop panicked"),
+        }
+
+        report(&mut reporter, ServingStatus::NotServing).await;
+    });
+
+    let gpt2 = Gpt2 {
+        generator_tx: tx,
+        tokenizer,
+        info,
+        metrics,
+    };
+
+    let reflection = match tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(gpt2_proto::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(
+            tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET,
+        )
+        .build()
+    {
+        Ok(reflection) => reflection,
+        Err(err) => {
+            error!(%err, "failed to build reflection service");
+            std::process::exit(1);
+        }
+    };
 
     let address = env::var("APP_ADDR").unwrap_or_else(|_| "127.0.0.1".into());
     let port = env::var("APP_PORT").unwrap_or_else(|_| "80".into());
@@ -75,6 +341,8 @@ async fn main() {
 
     Server::builder()
         .add_service(proto::Gpt2Server::new(gpt2))
+        .add_service(health)
+        .add_service(reflection)
         .serve(sockaddr)
         .await
         .unwrap();
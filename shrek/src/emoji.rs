@@ -1,70 +1,100 @@
-use chatbot::Chatbot;
-use futures::{stream, StreamExt};
-use rand::prelude::*;
+use chatbot::{Chatbot, Plugin, PluginError};
+use slack::Message;
 use time::{Duration, OffsetDateTime};
+use tokio::sync::Mutex;
 use tracing::trace;
 
-pub fn add(bot: &Chatbot) {
-    let conn = bot.slack();
-    let messages = bot.messages();
-
-    tokio::task::spawn(async move {
-        let cache = EmojiCache::new(conn.clone());
-
-        let rand = stream::unfold(cache, move |mut cache| async move {
-            let emoji = cache.choose().await.to_string();
-            Some((emoji, cache))
-        });
-
-        // TODO: this is very awkward
-        messages
-            .filter(|_| async { thread_rng().gen_bool(0.10) })
-            .zip(rand)
-            .map(|(msg, emoji)| (msg, emoji, &conn))
-            .for_each_concurrent(None, |(msg, emoji, conn)| async move {
-                trace!(%emoji, "reacting");
-                conn.react(&msg, &emoji).await.ok();
-            })
-            .await;
-    });
+/// Reacts to a random selection of messages with random emoji.
+#[derive(Default)]
+pub struct Emoji {
+    cache: Option<Mutex<EmojiCache>>,
+}
+
+#[async_trait::async_trait]
+impl Plugin for Emoji {
+    fn name(&self) -> &str {
+        "emoji"
+    }
+
+    async fn init(&mut self, bot: &Chatbot, _: &chatbot::Config) -> Result<(), PluginError> {
+        let cache = EmojiCache::new(bot.slack(), bot.rng().clone());
+        self.cache = Some(Mutex::new(cache));
+        Ok(())
+    }
+
+    async fn on_message(&self, bot: &Chatbot, msg: &Message) -> Result<(), PluginError> {
+        if !bot.rng().gen_bool(bot.probability(msg, 0.10)) {
+            return Ok(());
+        }
+
+        let cache = self.cache.as_ref().ok_or("not initialized")?;
+        let emoji = cache.lock().await.choose().await?.to_string();
+
+        // Reactions fail harmlessly, e.g. when the message already has this one.
+        trace!(%emoji, "reacting");
+        bot.slack().react(msg, &emoji).await.ok();
+
+        Ok(())
+    }
 }
 
 struct EmojiCache {
     age: OffsetDateTime,
     emoji: Vec<String>,
     slack: slack::Client,
+    rng: chatbot::Rng,
 }
 
 impl EmojiCache {
-    fn new(slack: slack::Client) -> EmojiCache {
+    fn new(slack: slack::Client, rng: chatbot::Rng) -> EmojiCache {
         EmojiCache {
             age: OffsetDateTime::UNIX_EPOCH,
             emoji: vec![],
             slack,
+            rng,
         }
     }
 
-    async fn choose(&mut self) -> &str {
+    async fn choose(&mut self) -> Result<&str, slack::Error> {
         if self.expired() {
-            self.emoji = Self::fetch(&self.slack).await;
+            self.emoji = Self::fetch(&self.slack).await?;
             self.age = OffsetDateTime::now_utc();
         }
 
-        self.emoji.choose(&mut thread_rng()).unwrap()
+        Ok(self.rng.choose(&self.emoji).unwrap())
     }
 
-    async fn fetch(slack: &slack::Client) -> Vec<String> {
+    async fn fetch(slack: &slack::Client) -> Result<Vec<String>, slack::Error> {
         let regular = gh_emoji::all().map(|e| e.0.to_string());
-        slack
-            .emoji_list()
-            .await
-            .unwrap()
-            .drain(..)
-            .chain(regular)
-            .collect()
+        let custom = slack.emoji_list().await?;
+
+        Ok(custom.into_iter().chain(regular).collect())
     }
 
     fn expired(&self) -> bool {
         (OffsetDateTime::now_utc() - self.age) > Duration::hours(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chatbot::testing::Harness;
+
+    #[tokio::test]
+    async fn reacts_when_forced() {
+        let harness = Harness::new().await;
+        harness.fake().add_emoji("onion");
+
+        let bot = harness.bot();
+        bot.policy()
+            .override_probability("emoji", "SWAMP", 1.0)
+            .unwrap();
+        bot.plugin(Emoji::default()).await.unwrap();
+
+        harness.say("SWAMP", "U1", "what are you doing in my swamp");
+        harness.settle().await;
+
+        assert_eq!(harness.reactions().len(), 1);
+    }
+}
@@ -1,96 +1,348 @@
-use eyre::{Context, Result};
-use futures::FutureExt;
-use gpt2_client::{GenerateRequest, Gpt2Client};
+use chatbot::{Chatbot, Config, Health, Plugin, PluginError, Setting};
+use eyre::{bail, Result};
+use gpt2_client::{
+    Candidate, Code, CountTokensRequest, GenerateRequest, Gpt2Client, HealthCheckRequest,
+    HealthClient, ModelInfoRequest, ServingStatus,
+};
+use once_cell::sync::OnceCell;
 use once_cell::unsync::Lazy;
 use regex::Regex;
+use slack::{Message, Timestamp};
 use std::borrow::Cow;
-use std::env;
-use tracing::{debug, error};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info};
 
 use crate::history::History;
 
-pub async fn add(bot: &chatbot::Chatbot, history: History) -> Result<()> {
-    let gpt2 = Gpt2::new(bot, history).await?;
+// How many tokens of reply to generate.
+const LENGTH: u32 = 100;
 
-    bot.reply_all_async(gpt2, |gpt2, msg| {
-        async move {
-            if msg.is_mention {
-                return None;
-            }
+// The most messages to put in a prompt, if they fit in the model's context.
+const SCRIPT_LINES: usize = 50;
 
-            gpt2.reply(msg).await
-        }
-        .boxed()
-    })?;
+// What the prompt ends with, for the model to continue.
+const CUE: &str = "SHREK:";
+
+// How many replies to generate, so that one that survives cleaning can be picked.
+const CANDIDATES: u32 = 3;
+
+// How long to wait at startup for gpt2_server to load its model, and how often to check.
+const READY_TIMEOUT: Duration = Duration::from_secs(600);
+const READY_INTERVAL: Duration = Duration::from_secs(5);
 
-    Ok(())
+// How often to edit a streamed reply as more text arrives. Slack rate limits chat.update.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replies to messages that mention shrek, ask a question, or reply to shrek, with text generated
+/// by gpt2_server. Replies can be streamed, i.e. posted as soon as generation starts and edited as
+/// text arrives, in which case they aren't paced.
+///
+/// Loading the model can take minutes, so the plugin starts out inactive and waits for gpt2_server
+/// in the background. If it isn't serving in time, the plugin is disabled.
+pub struct Gpt2 {
+    server: Arc<OnceCell<Server>>,
+    history: History,
+    stream: bool,
 }
 
-struct Gpt2 {
+// A gpt2_server that has loaded its model.
+struct Server {
     client: Gpt2Client,
-    history: History,
-    bot_id: String,
+    context_size: usize,
 }
 
 impl Gpt2 {
-    async fn new(bot: &chatbot::Chatbot, history: History) -> Result<Self> {
-        let address = env::var("GPT2_ADDRESS").wrap_err("gpt2_server address not specified")?;
-        let client = Gpt2Client::connect(address)
-            .await
-            .wrap_err("could not connect to gpt2_server")?;
-
-        Ok(Self {
-            client,
+    pub fn new(history: History) -> Self {
+        Self {
+            server: Arc::default(),
             history,
-            bot_id: bot.slack().bot_user_id().into(),
-        })
+            stream: false,
+        }
     }
 
-    async fn reply(&self, msg: &slack::Message) -> Option<String> {
+    fn should_reply(&self, bot_id: &str, msg: &Message) -> bool {
         // Is the triggering message in a thread that was started by our bot?
         let bot_reply = match self.history.parent(msg) {
-            Some(p) => p.user == self.bot_id,
+            Some(p) => p.user == bot_id,
             None => false,
         };
 
-        if !bot_reply && !should_reply(&msg.text) {
-            return None;
+        bot_reply || should_reply(&msg.text)
+    }
+
+    async fn request(&self, server: &Server, msg: &Message) -> Result<GenerateRequest> {
+        // Get as many of the messages leading up to our trigger message as fit.
+        let script = self.history.script(msg, SCRIPT_LINES).await?;
+        let script = self.fit(server, script).await?;
+
+        let prompt = format!("{}\n{}", script.join("\n"), CUE);
+        debug!(%prompt, "gpt2 prompt");
+
+        Ok(GenerateRequest {
+            length: LENGTH,
+            prompt,
+            // Stop generating once shrek's line is over, rather than generating text that would
+            // be stripped anyway.
+            stop_regex: vec![trailing_thoughts()],
+            ..Default::default()
+        })
+    }
+
+    // Drops the oldest lines of the script until the prompt leaves room in the model's context for
+    // the reply, rather than leaving gpt2_server to cut the prompt off partway through a line.
+    async fn fit(&self, server: &Server, script: Vec<String>) -> Result<Vec<String>> {
+        let texts = script
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .chain([CUE.to_string()])
+            .collect();
+
+        let mut client = server.client.clone();
+        let request = CountTokensRequest { texts };
+        let mut counts = client.count_tokens(request).await?.into_inner().counts;
+
+        let cue = counts.pop().unwrap_or_default() as usize;
+        let budget = server.context_size.saturating_sub(LENGTH as usize + cue);
+
+        fit(script, &counts, budget)
+    }
+
+    // Returns None if none of the candidates had anything worth saying.
+    async fn generate(&self, server: &Server, msg: &Message) -> Result<Option<String>> {
+        let request = GenerateRequest {
+            num_return_sequences: Some(CANDIDATES),
+            ..self.request(server, msg).await?
+        };
+
+        let mut client = server.client.clone();
+        let generated = client.generate_text(request).await?.into_inner();
+
+        debug!(candidates = ?generated.candidates, "gpt2 text generated");
+
+        Ok(best(&generated.candidates))
+    }
+
+    // Posts the reply as soon as there's some text, then edits it as more arrives.
+    async fn stream(&self, bot: &Chatbot, server: &Server, msg: &Message) -> Result<()> {
+        let request = self.request(server, msg).await?;
+
+        let mut client = server.client.clone();
+        let mut chunks = client.generate_text_stream(request).await?.into_inner();
+
+        let slack = bot.slack();
+        let thread = msg.thread_ts.as_ref();
+        let mut text = String::new();
+        let mut shown = String::new();
+        let mut posted: Option<(Timestamp, Instant)> = None;
+
+        while let Some(chunk) = chunks.message().await? {
+            text.push_str(&chunk.text);
+
+            let partial = strip_trailing_thoughts(&text).trim();
+            if partial.is_empty() || partial == shown {
+                continue;
+            }
+
+            match &mut posted {
+                None => {
+                    let ts = slack.post_updatable(&msg.channel, partial, thread).await?;
+                    posted = Some((ts, Instant::now()));
+                }
+                Some((ts, updated)) if updated.elapsed() >= UPDATE_INTERVAL => {
+                    slack.update(&msg.channel, ts, partial).await?;
+                    *updated = Instant::now();
+                }
+                Some(_) => continue,
+            }
+
+            shown = partial.to_string();
         }
 
-        match self.prompt(msg).await {
-            Ok(r) => Some(r),
-            Err(err) => {
-                error!(%err, "failed to generate reply");
-                None
+        debug!(%text, "gpt2 text streamed");
+
+        let reply = clean(&text);
+        if reply.is_empty() || reply == shown {
+            return Ok(());
+        }
+
+        match posted {
+            Some((ts, _)) => slack.update(&msg.channel, &ts, &reply).await?,
+            None => slack.post(&msg.channel, &reply, thread).await?,
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for Gpt2 {
+    fn name(&self) -> &str {
+        "gpt2"
+    }
+
+    fn settings(&self) -> Vec<Setting> {
+        vec![
+            Setting::required("GPT2_ADDRESS", "gpt2_server address"),
+            Setting::optional(
+                "GPT2_STREAM",
+                "stream replies as they're generated",
+                "false",
+            ),
+        ]
+    }
+
+    async fn init(&mut self, bot: &Chatbot, config: &Config) -> Result<(), PluginError> {
+        let address = config.get("GPT2_ADDRESS").unwrap_or_default().to_string();
+        self.stream = config.get("GPT2_STREAM") == Some("true");
+
+        let (server, bot) = (self.server.clone(), bot.clone());
+        let supervisor = bot.supervisor().clone();
+        supervisor.spawn("gpt2_connect", move || {
+            let (server, bot, address) = (server.clone(), bot.clone(), address.clone());
+
+            async move {
+                if server.get().is_some() {
+                    return;
+                }
+
+                match connect(&bot, &address).await {
+                    Ok(Some(connected)) => {
+                        server.set(connected).ok();
+                    }
+                    // The bot stopped first.
+                    Ok(None) => {}
+                    Err(error) => {
+                        error!(%error, "gpt2_server isn't available, disabling gpt2");
+                        bot.plugins().disable("gpt2").ok();
+                    }
+                }
             }
+        });
+
+        Ok(())
+    }
+
+    async fn on_message(&self, bot: &Chatbot, msg: &Message) -> Result<(), PluginError> {
+        let server = match self.server.get() {
+            Some(server) => server,
+            None => return Ok(()),
+        };
+
+        if msg.is_mention || !self.should_reply(bot.slack().bot_user_id(), msg) {
+            return Ok(());
         }
+
+        // Check the cooldown before generating, so that rate limited users don't cost a
+        // generation.
+        if !bot.permit(msg).await {
+            return Ok(());
+        }
+
+        if self.stream {
+            return Ok(self.stream(bot, server, msg).await?);
+        }
+
+        match self.generate(server, msg).await? {
+            Some(reply) => bot.respond(msg, &reply).await?,
+            None => debug!("nothing worth replying with"),
+        }
+
+        Ok(())
     }
 
-    async fn prompt(&self, msg: &slack::Message) -> Result<String> {
-        // Get the 20 messages leading up to our trigger message.
-        let script = self.history.script(msg, 20).await?;
+    fn health(&self) -> Health {
+        match self.server.get() {
+            Some(_) => Health::Healthy,
+            None => Health::Unhealthy("gpt2_server isn't ready".into()),
+        }
+    }
+}
 
-        let prompt = format!("{}\nSHREK:", script);
-        debug!(%prompt, "gpt2 prompt");
+// Waits for gpt2_server to load its model, then connects to it. Returns None if the bot stops
+// first.
+async fn connect(bot: &Chatbot, address: &str) -> Result<Option<Server>, PluginError> {
+    if !wait_until_ready(bot, address).await? {
+        return Ok(None);
+    }
+
+    let mut client = Gpt2Client::connect(address.to_string())
+        .await
+        .map_err(|err| format!("could not connect to gpt2_server: {}", err))?;
 
-        let mut client = self.client.clone();
-        let text = client
-            .generate_text(GenerateRequest {
-                length: 100,
-                prompt,
-            })
-            .await?
-            .into_inner()
-            .text;
+    let info = client
+        .model_info(ModelInfoRequest {})
+        .await
+        .map_err(|err| format!("could not get gpt2_server model info: {}", err))?
+        .into_inner();
 
-        debug!(%text, "gpt2 text generated");
+    info!(model = %info.name, context_size = info.context_size, "gpt2_server ready");
+
+    Ok(Some(Server {
+        client,
+        context_size: info.context_size as usize,
+    }))
+}
+
+// Waits until gpt2_server reports that it's serving, i.e. it has loaded its model. Returns false
+// if the bot stops first.
+async fn wait_until_ready(bot: &Chatbot, address: &str) -> Result<bool, PluginError> {
+    let request = HealthCheckRequest {
+        service: "gpt2.Gpt2".into(),
+    };
+    let deadline = tokio::time::Instant::now() + READY_TIMEOUT;
+
+    loop {
+        // The server may still be starting, in which case it isn't ready yet either.
+        match HealthClient::connect(address.to_string()).await {
+            Ok(mut health) => match health.check(request.clone()).await {
+                Ok(response) if response.get_ref().status() == ServingStatus::Serving => {
+                    return Ok(true)
+                }
+                Ok(_) => info!("waiting for gpt2_server to load its model"),
+                // Servers without health checking are ready once they accept connections.
+                Err(status) if status.code() == Code::Unimplemented => return Ok(true),
+                Err(status) => {
+                    return Err(format!("gpt2_server health check failed: {}", status).into())
+                }
+            },
+            Err(error) => info!(%error, "waiting for gpt2_server to start"),
+        }
 
-        let raw = strip_trailing_thoughts(&text);
-        let clean = strip_incomplete_sentences(raw);
-        Ok(clean.to_string())
+        if tokio::time::Instant::now() >= deadline {
+            return Err("gpt2_server didn't become ready in time".into());
+        }
+
+        if !bot.supervisor().sleep(READY_INTERVAL).await {
+            return Ok(false);
+        }
     }
 }
 
+// The newest lines whose token counts add up to no more than the budget. Lines are counted
+// separately, which is close enough to how they tokenize once joined.
+fn fit(mut script: Vec<String>, counts: &[u32], budget: usize) -> Result<Vec<String>> {
+    if counts.len() != script.len() {
+        bail!(
+            "gpt2_server counted {} lines, expected {}",
+            counts.len(),
+            script.len()
+        );
+    }
+
+    let mut total = 0;
+    let fits = counts
+        .iter()
+        .rev()
+        .take_while(|&&count| {
+            total += count as usize;
+            total <= budget
+        })
+        .count();
+
+    Ok(script.split_off(script.len() - fits))
+}
+
 // Does the input contain a reply trigger?
 fn should_reply(input: &str) -> bool {
     // Reply to any message that mentions shrek, or ends in a question mark.
@@ -98,6 +350,31 @@ fn should_reply(input: &str) -> bool {
     re.is_match(input)
 }
 
+// Picks the best candidate that is still a complete reply after cleaning, or failing that, the
+// best that isn't empty. Candidates are ordered best first.
+fn best(candidates: &[Candidate]) -> Option<String> {
+    let cleaned: Vec<_> = candidates
+        .iter()
+        .map(|c| {
+            let clean = clean(&c.text).trim().to_string();
+            let complete = clean == strip_trailing_thoughts(&c.text).trim();
+            (clean, complete)
+        })
+        .filter(|(clean, _)| !clean.is_empty())
+        .collect();
+
+    let complete = cleaned.iter().find(|(_, complete)| *complete);
+    complete
+        .or_else(|| cleaned.first())
+        .map(|(clean, _)| clean.clone())
+}
+
+// Strips everything but shrek's next line, and any incomplete sentences at the end of it.
+fn clean(text: &str) -> String {
+    let raw = strip_trailing_thoughts(text);
+    strip_incomplete_sentences(raw).to_string()
+}
+
 // Remove any trailing incomplete sentences, while allowing a standalone sentence fragment
 fn strip_incomplete_sentences(input: &str) -> Cow<'_, str> {
     // Define the "punc" named capture group, and have the whole regex match a period, exclamation
@@ -116,31 +393,100 @@ fn strip_incomplete_sentences(input: &str) -> Cow<'_, str> {
 // only interested shrek's next "line". This will will find the boundaries of that line, and remove
 // whatever follows.
 fn strip_trailing_thoughts(input: &str) -> &str {
-    let re = Lazy::new(|| {
-        // Scriptlikes, e.g "DONKEY: "
-        let scriptlikes = r"(:?\b|^)[[:upper:][:punct:]&&[^:] ]{3,}:";
+    let re = Lazy::new(|| Regex::new(&trailing_thoughts()).unwrap());
 
-        // More permissive script-likes, but only at line starts
-        let line_start = r"^[[:word:][:punct:]#.']{3,}:";
+    // Collect into a vector so we can log it.
+    let split: Vec<_> = re.splitn(input, 2).collect();
+    debug!(?split);
 
-        // Lines that only have stage direction, e.g. `[Shrek kisses Fiona]`
-        let stage_direction = r"^[(\[].*[)\]]$";
+    split.first().unwrap()
+}
 
-        // Stop claiming that people have left the conversation.
-        let left_convo = r"has left the conversation.$";
+// The pattern for the start of whatever follows shrek's line.
+fn trailing_thoughts() -> String {
+    // Scriptlikes, e.g "DONKEY: "
+    let scriptlikes = r"(:?\b|^)[[:upper:][:punct:]&&[^:] ]{3,}:";
 
-        // All patterns combined into an alternation, with multi-line mode enabled. Note that
-        // we cannot use a RegexSet here, since those don't support splitting.
-        let combined = format!("(?m){}|{}|{}|{}", scriptlikes, line_start, stage_direction, left_convo);
+    // More permissive script-likes, but only at line starts
+    let line_start = r"^[[:word:][:punct:]#.']{3,}:";
 
-        Regex::new(&combined).unwrap()
-    });
+    // Lines that only have stage direction, e.g. `[Shrek kisses Fiona]`
+    let stage_direction = r"^[(\[].*[)\]]$";
 
-    // Collect into a vector so we can log it.
-    let split: Vec<_> = re.splitn(input, 2).collect();
-    debug!(?split);
+    // Stop claiming that people have left the conversation.
+    let left_convo = r"has left the conversation.$";
 
-    split.first().unwrap()
+    // All patterns combined into an alternation, with multi-line mode enabled. Note that
+    // we cannot use a RegexSet here, since those don't support splitting.
+    format!(
+        "(?m){}|{}|{}|{}",
+        scriptlikes, line_start, stage_direction, left_convo
+    )
 }
 
-// TODO: unit test regex
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chatbot::testing::Harness;
+
+    fn candidate(text: &str) -> Candidate {
+        Candidate {
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fits_scripts_in_budget() {
+        let script = || vec!["A: a".to_string(), "B: b b".into(), "C: c".into()];
+
+        let fit = |counts: &[u32], budget| fit(script(), counts, budget);
+
+        assert_eq!(fit(&[3, 4, 3], 10).unwrap(), ["A: a", "B: b b", "C: c"]);
+        assert_eq!(fit(&[3, 4, 3], 9).unwrap(), ["B: b b", "C: c"]);
+        assert_eq!(fit(&[3, 4, 3], 2).unwrap(), Vec::<String>::new());
+
+        // Counts that don't match the script are an error, rather than a guess.
+        assert!(fit(&[3, 4, 3, 1], 10).is_err());
+    }
+
+    #[test]
+    fn picks_complete_candidates() {
+        let candidates = [
+            candidate("\nDONKEY: hey"),
+            candidate("Get out of my swamp! And take"),
+            candidate("Ogres are like onions.\nDONKEY: they stink?"),
+        ];
+
+        assert_eq!(best(&candidates).unwrap(), "Ogres are like onions.");
+        assert_eq!(best(&candidates[..2]).unwrap(), "Get out of my swamp!");
+        assert_eq!(best(&candidates[..1]), None);
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_server_in_the_background() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        let history = History::new(bot.slack(), bot.opt_outs().clone());
+
+        bot.plugin_with(Gpt2::new(history), |key| match key {
+            "GPT2_ADDRESS" => Some("http://127.0.0.1:1".into()),
+            _ => None,
+        })
+        .await
+        .unwrap();
+
+        // The plugin is added straight away, but stays quiet until the server is ready.
+        assert!(bot.plugins().enabled("gpt2"));
+        harness.say("C1", "U1", "shrek?");
+        harness.settle().await;
+        assert!(harness.actions().is_empty());
+
+        // A server that never becomes ready disables it.
+        let deadline = tokio::time::Instant::now() + READY_TIMEOUT * 2;
+        while bot.plugins().enabled("gpt2") && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(READY_INTERVAL).await;
+        }
+        assert!(!bot.plugins().enabled("gpt2"));
+    }
+}
@@ -1,13 +1,12 @@
 use eyre::{eyre, Result};
 use futures::{
-    channel::mpsc,
     future::ready,
-    sink::SinkExt,
     stream,
     stream::{Stream, StreamExt, TryStreamExt},
 };
 use std::collections::HashMap;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use tokio::sync::broadcast;
 use tracing::debug;
@@ -16,58 +15,100 @@ use tracing::debug;
 pub struct History {
     workspace: Arc<RwLock<Workspace>>,
     users: UserCache,
+    opt_outs: chatbot::OptOuts,
     changed: broadcast::Sender<()>,
+
+    // Set once messages stop being recorded, when the bot stops.
+    ended: Arc<AtomicBool>,
 }
 
 impl History {
-    pub fn new(slack: slack::Client) -> Self {
+    pub fn new(slack: slack::Client, opt_outs: chatbot::OptOuts) -> Self {
         let (changed, _) = broadcast::channel(1);
         Self {
             workspace: Arc::new(RwLock::new(Workspace::default())),
             users: UserCache::new(slack),
+            opt_outs,
             changed,
+            ended: Arc::default(),
         }
     }
 
     pub async fn monitor(&self, bot: &chatbot::Chatbot) -> Result<()> {
-        let (tx, rx) = mpsc::unbounded::<Arc<slack::Message>>();
-
-        let workspace = self.workspace.clone();
-        let changed = self.changed.clone();
+        self.resync(&bot.slack()).await?;
+
+        let history = self.clone();
+        let raw = bot.clone();
+        bot.supervisor().spawn("history", move || {
+            let (history, messages) = (history.clone(), raw.raw_messages());
+
+            async move {
+                messages
+                    .for_each(|msg| {
+                        history.record(msg);
+                        ready(())
+                    })
+                    .await;
+
+                // Nothing else is coming, so stop waiting for it.
+                history.ended.store(true, Ordering::SeqCst);
+                history.changed.send(()).ok();
+            }
+        });
 
-        tokio::task::spawn(async move {
-            rx.filter(|msg| ready(!msg.is_mention))
-                .for_each(|msg| {
-                    workspace.write().unwrap().insert(msg);
-                    changed.send(()).ok();
+        // Forget everything said by users who opt out.
+        let (workspace, supervisor) = (self.workspace.clone(), bot.supervisor().clone());
+        let opt_outs = self.opt_outs.clone();
+        bot.supervisor().spawn("history_purge", move || {
+            let workspace = workspace.clone();
+
+            opt_outs
+                .opted_out()
+                .take_until(supervisor.stopped())
+                .for_each(move |user| {
+                    debug!(%user, "purging history for opted out user");
+                    workspace.write().unwrap().purge(&user);
                     ready(())
                 })
-                .await;
         });
 
-        Self::send_history(&bot.slack(), tx.clone()).await?;
+        Ok(())
+    }
 
-        tokio::task::spawn(bot.raw_messages().map(Ok).forward(tx));
+    /// Loads every channel's history, e.g. to catch up on messages missed while disconnected.
+    /// Returns the number of messages loaded.
+    pub async fn resync(&self, slack: &slack::Client) -> Result<usize> {
+        let mut count = 0;
+
+        Self::load_history(slack)
+            .try_for_each(|msg| {
+                self.record(Arc::new(msg));
+                count += 1;
+                ready(Ok(()))
+            })
+            .await?;
 
-        Ok(())
+        Ok(count)
     }
 
-    async fn send_history(
-        slack: &slack::Client,
-        tx: mpsc::UnboundedSender<Arc<slack::Message>>,
-    ) -> Result<()> {
-        let sink = tx.sink_err_into::<eyre::Error>();
+    fn record(&self, msg: Arc<slack::Message>) {
+        if msg.is_mention || self.opt_outs.contains(&msg.user) {
+            return;
+        }
 
-        stream::iter(slack.channel_ids().await?)
-            .then(|chan| Self::channel_history(slack, chan))
+        self.workspace.write().unwrap().insert(msg);
+        self.changed.send(()).ok();
+    }
+
+    fn load_history(slack: &slack::Client) -> impl Stream<Item = Result<slack::Message>> + '_ {
+        stream::once(slack.channel_ids())
+            .map_ok(|ids| stream::iter(ids).map(Ok))
+            .err_into::<eyre::Error>()
             .try_flatten()
-            .and_then(|msg| Self::thread_history(slack, msg))
+            .and_then(move |chan| Self::channel_history(slack, chan))
+            .try_flatten()
+            .and_then(move |msg| Self::thread_history(slack, msg))
             .try_flatten()
-            .map_ok(Arc::new)
-            .forward(sink)
-            .await?;
-
-        Ok(())
     }
 
     async fn channel_history(
@@ -91,8 +132,11 @@ impl History {
         Ok(stream::iter(thread).map(Ok))
     }
 
-    pub async fn script(&self, msg: &slack::Message, length: usize) -> Result<String> {
-        self.wait(msg).await;
+    /// Up to `length` lines of script leading up to and including the message, oldest first.
+    pub async fn script(&self, msg: &slack::Message, length: usize) -> Result<Vec<String>> {
+        if !self.wait(msg).await {
+            return Err(eyre!("history stopped before the message was recorded"));
+        }
 
         // TODO: rework with tokio rwlock
         let messages: Vec<_> = {
@@ -113,16 +157,24 @@ impl History {
 
         script.reverse();
 
-        Ok(script.join("\n"))
+        Ok(script)
     }
 
-    async fn wait(&self, msg: &slack::Message) {
+    // Waits for the message to be recorded. Returns false if it never will be, because the bot is
+    // stopping.
+    async fn wait(&self, msg: &slack::Message) -> bool {
         let mut changed = self.changed.subscribe();
 
         while !self.contains(msg) {
+            if self.ended.load(Ordering::SeqCst) {
+                return false;
+            }
+
             debug!(ts=%msg.ts, channel=%msg.channel, "message not found in history, waiting");
             changed.recv().await.ok();
         }
+
+        true
     }
 
     fn contains(&self, msg: &slack::Message) -> bool {
@@ -132,7 +184,9 @@ impl History {
 
     pub fn parent(&self, msg: &slack::Message) -> Option<Arc<slack::Message>> {
         let workspace = self.workspace.read().unwrap();
-        workspace.parent(msg)
+        workspace
+            .parent(msg)
+            .filter(|parent| !self.opt_outs.contains(&parent.user))
     }
 }
 
@@ -155,6 +209,12 @@ impl Workspace {
         }
     }
 
+    fn purge(&mut self, user: &str) {
+        for channel in self.channels.values_mut() {
+            channel.purge(user);
+        }
+    }
+
     fn history(&self, msg: &slack::Message) -> Option<impl Iterator<Item = &slack::Message>> {
         self.channels.get(&msg.channel).map(|c| c.history(msg))
     }
@@ -210,6 +270,13 @@ impl Channel {
     fn parent(&self, msg: &slack::Message) -> Option<Arc<slack::Message>> {
         msg.thread_ts.as_ref().and_then(|ts| self.main.get(ts))
     }
+
+    fn purge(&mut self, user: &str) {
+        self.main.purge(user);
+        for thread in self.threads.values_mut() {
+            thread.purge(user);
+        }
+    }
 }
 
 #[derive(Default, Debug)]
@@ -228,8 +295,10 @@ impl Thread {
     }
 
     fn insert(&mut self, msg: Arc<slack::Message>) {
-        self.thread
-            .insert(self.find(&msg.ts).unwrap_or_else(|x| x), msg);
+        match self.find(&msg.ts) {
+            Ok(idx) => self.thread[idx] = msg,
+            Err(idx) => self.thread.insert(idx, msg),
+        }
     }
 
     fn contains(&self, ts: &slack::Timestamp) -> bool {
@@ -239,6 +308,10 @@ impl Thread {
     fn get(&self, ts: &slack::Timestamp) -> Option<Arc<slack::Message>> {
         self.find(ts).ok().map(|idx| self.thread[idx].clone())
     }
+
+    fn purge(&mut self, user: &str) {
+        self.thread.retain(|m| m.user != user);
+    }
 }
 
 // TODO: handle username updates (events?)
@@ -279,46 +352,63 @@ impl UserCache {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use dotenv::dotenv;
-    use futures::stream::StreamExt;
-    use std::env;
+    use chatbot::testing::Harness;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn history() {
-        dotenv().unwrap();
-
-        let client = slack::Client {
-            http: reqwest::Client::new(),
-            app_token: env::var("APP_TOKEN").unwrap(),
-            bot_token: env::var("BOT_TOKEN").unwrap(),
-        };
-
-        let (driver, messages) = client.messages();
-        tokio::task::spawn(driver);
-
-        let bot = chatbot::Chatbot::new(client).await.unwrap();
-        let mut history = History::new(bot.slack());
-        history.monitor(&bot).await.unwrap();
-        let mut raw = Box::pin(bot.raw_messages());
-
-        tokio::task::spawn(async move { bot.run(messages).await });
-
-        /*
-        let msg = slack::Message {
-            text: "asdf".into(),
-            user: "UL6H0F39R".into(),
-            ts: "1636048583.000400".into(),
-            thread_ts: Some("1636047059.000300".into()),
-            reply_count: 0,
-            channel: "CLXKXACCF".into(),
-        };
-        */
-
-        println!("done waiting for monitor");
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        harness.fake().set_display_name("U1", "Donkey");
+        harness.fake().set_display_name("U2", "Fiona");
+
+        let parent = harness.message("C1", "U1", "hey shrek");
+        let mut reply = harness.message("C1", "U2", "  what  ");
+        reply.thread_ts = Some(parent.ts.clone());
+        harness.fake().add_history(parent.clone());
+        harness.fake().add_history(reply);
+        harness
+            .fake()
+            .add_history(harness.message("C1", "U2", "elsewhere"));
+
+        let history = History::new(bot.slack(), bot.opt_outs().clone());
+        history.monitor(bot).await.unwrap();
+
+        let msg = harness.reply(&parent, "U1", "are you there?");
+        let script = history.script(&msg, 5).await.unwrap();
+        assert_eq!(
+            script,
+            ["DONKEY: hey shrek", "FIONA: what", "DONKEY: are you there?"]
+        );
+
+        // Resyncing doesn't duplicate messages that are already known.
+        assert_eq!(history.resync(&bot.slack()).await.unwrap(), 3);
+        let script = history.script(&msg, 5).await.unwrap();
+        assert_eq!(
+            script,
+            ["DONKEY: hey shrek", "FIONA: what", "DONKEY: are you there?"]
+        );
+
+        bot.opt_outs().opt_out("U2").unwrap();
+        harness.settle().await;
+
+        let script = history.script(&msg, 5).await.unwrap();
+        assert_eq!(script, ["DONKEY: hey shrek", "DONKEY: are you there?"]);
+    }
 
-        while let Some(msg) = raw.next().await {
-            let script = history.script(&msg, 5).await.unwrap();
-            println!("{}\n\n", script);
-        }
+    #[tokio::test]
+    async fn stops_waiting_when_the_bot_stops() {
+        let harness = Harness::new().await;
+        let bot = harness.bot();
+        let history = History::new(bot.slack(), bot.opt_outs().clone());
+        history.monitor(bot).await.unwrap();
+
+        // A message that never arrives.
+        let msg = harness.message("C1", "U1", "hey shrek");
+        let script = history.script(&msg, 5);
+
+        bot.supervisor().stop();
+        let script = tokio::time::timeout(Duration::from_secs(1), script).await;
+        assert!(script.unwrap().is_err());
     }
 }
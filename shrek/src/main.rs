@@ -1,16 +1,23 @@
-use chatbot::Chatbot;
+use chatbot::{Chatbot, Cooldown, ErrorPolicy, Missed, Notice, Pacing, Schedule, Shadow};
 use dotenv::dotenv;
-use eyre::{eyre, Result};
+use eyre::Result;
 use futures::FutureExt;
-use rand::prelude::*;
 use std::env;
-use tracing::debug;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
 mod emoji;
 mod gpt2;
 mod history;
+mod speak;
 
+use emoji::Emoji;
+use gpt2::Gpt2;
 use history::History;
+use speak::Speak;
+
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -28,97 +35,201 @@ async fn main() -> Result<()> {
     let client = slack::Client::new(env::var("APP_TOKEN")?, env::var("BOT_TOKEN")?).await?;
 
     let (driver, messages) = client.messages();
-    tokio::task::spawn(driver);
+    let driver = tokio::task::spawn(driver);
+
+    // Let people know when shrek fails them, and tell the admins why.
+    let mut errors = ErrorPolicy::new().notice(Notice::React("x".into()));
+    if let Ok(channel) = env::var("ERROR_CHANNEL") {
+        errors = errors.admin_channel(&channel);
+    }
+
+    let chatbot = Chatbot::new(client.clone())
+        .await?
+        .with_error_policy(errors);
 
-    let chatbot = chatbot::Chatbot::new(client.clone()).await?;
+    let opt_outs = env::var("OPTOUT_PATH").unwrap_or_else(|_| "optouts.json".into());
+    chatbot.opt_outs().load(opt_outs)?;
+    chatbot.opt_out_commands()?;
 
-    let history = History::new(chatbot.slack());
+    let history = History::new(chatbot.slack(), chatbot.opt_outs().clone());
     history.monitor(&chatbot).await?;
 
     configure(&chatbot, &history).await?;
+
+    let supervisor = chatbot.supervisor().clone();
+    tokio::task::spawn(async move {
+        match terminated().await {
+            Ok(()) => supervisor.stop(),
+            Err(error) => error!(%error, "failed to listen for signals"),
+        }
+    });
+
     chatbot.run(messages).await?;
 
+    // Give in-flight replies (e.g. from gpt2 or uberduck) a chance to finish.
+    if !chatbot.shutdown(SHUTDOWN_DEADLINE).await {
+        warn!("some handlers didn't finish before the shutdown deadline");
+    }
+
+    driver.await?;
+
+    Ok(())
+}
+
+// Resolves on SIGTERM, which is sent on deploys, or ctrl-c.
+async fn terminated() -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("SIGTERM received"),
+        res = tokio::signal::ctrl_c() => res?,
+    }
+
     Ok(())
 }
 
 async fn configure(chatbot: &Chatbot, history: &History) -> Result<()> {
-    chatbot
-        .reply("(?i)^(?:fuck|thank).*shrek", "You're welcome!")?
-        .reply("(?i)^shrek no$", "SHREK YES")?;
-
-    chatbot.reply_with("echo (.*)", |_, cap| Some(cap[1].to_string()))?;
-
-    chatbot.reply_with("(?i)give (him|her|them) the (.*)", |_, cap| {
-        Some(format!(
-            "DON'T GIVE {} THE {}",
-            cap[1].to_uppercase(),
-            cap[2].to_uppercase()
-        ))
-    })?;
+    // Make runs reproducible, or force handlers to trigger, e.g. in a test workspace.
+    if let Ok(seed) = env::var("RNG_SEED") {
+        chatbot.rng().reseed(seed.parse()?);
+    }
 
-    chatbot.reply_all(|_| {
-        thread_rng()
-            .gen_bool(0.01)
-            .then(|| "SHREK IS LOVE, SHREK IS LIFE".into())
-    })?;
+    if let Ok(overrides) = env::var("PROBABILITY_OVERRIDES") {
+        chatbot.policy().override_probabilities(&overrides)?;
+    }
 
-    chatbot.reply_all(cronk)?;
+    chatbot.global_layer(chatbot::Trace);
 
-    emoji::add(chatbot);
-    gpt2::add(chatbot, history.clone()).await?;
-    uberduck(chatbot, history.clone())?;
+    // Admins can pause shrek, change policies and plugins, and reload config.
+    if let Ok(admins) = env::var("ADMINS") {
+        for admin in admins.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            chatbot.admins().add(admin);
+        }
+    }
 
-    Ok(())
-}
+    if let Ok(group) = env::var("ADMIN_GROUP") {
+        chatbot.admins().set_usergroup(&group);
+    }
 
-fn uberduck(bot: &Chatbot, history: History) -> Result<()> {
-    let uber = uberduck::Client {
-        http: reqwest::Client::new(),
-        api_key: env::var("UBERDUCK_API_KEY").unwrap(),
-        api_secret: env::var("UBERDUCK_API_SECRET").unwrap(),
-    };
+    if chatbot.admins().refresh(&chatbot.slack()).await? == 0 {
+        warn!("no admins configured, privileged commands are disabled");
+    }
+
+    chatbot.admin_commands()?;
+
+    let policy = env::var("POLICY_PATH").unwrap_or_else(|_| "policy.json".into());
+    chatbot.policy().load(policy)?;
+    chatbot.policy_commands()?;
 
-    bot.listen((uber, history), |(uber, history), slack, msg| {
+    let path = env::var("RULES_PATH").unwrap_or_else(|_| "shrek/rules.toml".into());
+    let rules = chatbot.rules(path)?;
+
+    let admins = chatbot.privileged();
+    admins.listen((rules, chatbot.clone()), |(rules, bot), slack, msg| {
         async move {
-            if !msg.is_mention || !msg.text.contains("speak") {
+            if chatbot::command(slack, msg) != Some("reload") {
                 return Ok(());
             }
 
-            let parent = history
-                .parent(msg)
-                .ok_or_else(|| eyre!("couldn't find parent"))?;
+            let mut text = match rules.reload() {
+                Ok(count) => format!("Reloaded {} rules.", count),
+                Err(err) => format!("Couldn't reload rules: {}", err),
+            };
 
-            debug!(text=%parent.text, "speaking");
+            match bot.admins().refresh(slack).await {
+                Ok(count) => text.push_str(&format!(" {} admins.", count)),
+                Err(err) => text.push_str(&format!(" Couldn't refresh admins: {}", err)),
+            }
 
-            let uuid = uber.speak(&parent.text).await?;
-            let url = uber.wait(&uuid).await?;
-            let wav = uber.download(&url).await?;
+            slack
+                .post(&msg.channel, &text, msg.thread_ts.as_ref())
+                .await
+        }
+        .boxed()
+    })?;
+
+    admins.listen(history.clone(), |history, slack, msg| {
+        async move {
+            if chatbot::command(slack, msg) != Some("resync") {
+                return Ok(());
+            }
 
-            let filename = format!("{:0.20}.wav", &parent.text);
-            slack.upload_reply(&parent, &filename, wav).await?;
+            let text = match history.resync(slack).await {
+                Ok(count) => format!("Resynced {} messages.", count),
+                Err(err) => format!("Couldn't resync history: {}", err),
+            };
 
-            Ok::<(), eyre::Error>(())
+            slack
+                .post(&msg.channel, &text, msg.thread_ts.as_ref())
+                .await
         }
         .boxed()
     })?;
 
+    // Keep excitable users from turning shrek into a loop, and have him take his time typing
+    // replies, a few sentences at a time.
+    let limited = chatbot
+        .with_cooldown(
+            Cooldown::new()
+                .per_user(3, Duration::from_secs(60))
+                .per_channel(10, Duration::from_secs(60))
+                .notice(Notice::React("ice_cube".into())),
+        )
+        .with_pacing(Pacing::new().split(200));
+
+    limited.reply_with("echo (.*)", |_, cap| Some(cap[1].to_string()))?;
+
+    chatbot.plugin(Emoji::default()).await?;
+    // Try out changes to gpt2 on live traffic without letting it speak, reporting what it would
+    // have said to GPT2_SHADOW, if that's a channel.
+    let gpt2 = match env::var("GPT2_SHADOW") {
+        Ok(channel) if channel.is_empty() => limited.layer(Shadow::new()),
+        Ok(channel) => limited.layer(Shadow::new().channel(&channel)),
+        Err(_) => limited.clone(),
+    };
+
+    gpt2.plugin(Gpt2::new(history.clone())).await?;
+    chatbot.plugin(Speak::new(history.clone())).await?;
+    chatbot.plugin_commands()?;
+
+    schedule(chatbot)?;
+
     Ok(())
 }
 
-fn cronk(_: &slack::Message) -> Option<String> {
-    let mut rng = thread_rng();
-
-    let cronk = [
-        "Cronk.",
-        "Cronk is good.",
-        "Buy Cronk.",
-        "Drink Cronk.",
-        "Dr. Cronk.",
-        ":point_right: Who said Cronk was dead?",
-        ":point_right: Drink Cronk and be happy",
-    ];
-
-    rng.gen_bool(0.01)
-        .then(|| cronk.choose(&mut rng))?
-        .map(|c| c.to_string())
+// Periodic posts, only enabled if a channel to post them in is configured.
+fn schedule(bot: &Chatbot) -> Result<()> {
+    let channel = match env::var("SCHEDULE_CHANNEL") {
+        Ok(c) => c,
+        Err(_) => return Ok(()),
+    };
+
+    let path = env::var("SCHEDULES_PATH").unwrap_or_else(|_| "schedules.json".into());
+    bot.schedules().load(path)?;
+
+    let tz = env::var("SCHEDULE_TZ").unwrap_or_else(|_| "UTC".into());
+
+    let quote = Schedule::cron("quote_of_the_week", "0 0 9 * * Mon", &tz)?
+        .jitter(Duration::from_secs(15 * 60))
+        .missed(Missed::RunOnce);
+
+    let rng = bot.rng().clone();
+    bot.post_on(quote, &channel, move || {
+        let quotes = [
+            "Ogres are like onions.",
+            "This is the part where you run away.",
+            "Better out than in, I always say.",
+            "What are you doing in my swamp?!",
+            "Do you know the Muffin Man?",
+        ];
+
+        rng.choose(&quotes)
+            .map(|q| format!("Shrek quote of the week: _{}_", q))
+    })?;
+
+    let cronk =
+        Schedule::cron("daily_cronk", "0 0 12 * * *", &tz)?.jitter(Duration::from_secs(3600));
+    bot.post_on(cronk, &channel, || Some("Cronk.".into()))?;
+
+    Ok(())
 }
@@ -0,0 +1,66 @@
+use chatbot::{Chatbot, Config, Plugin, PluginError, Setting};
+use slack::Message;
+use tracing::debug;
+
+use crate::history::History;
+
+/// Replies to `@shrek speak` with a recording of the message being replied to, voiced by
+/// uberduck.
+pub struct Speak {
+    client: Option<uberduck::Client>,
+    history: History,
+}
+
+impl Speak {
+    pub fn new(history: History) -> Self {
+        Self {
+            client: None,
+            history,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for Speak {
+    fn name(&self) -> &str {
+        "uberduck"
+    }
+
+    fn settings(&self) -> Vec<Setting> {
+        vec![
+            Setting::required("UBERDUCK_API_KEY", "uberduck API key"),
+            Setting::required("UBERDUCK_API_SECRET", "uberduck API secret"),
+        ]
+    }
+
+    async fn init(&mut self, _: &Chatbot, config: &Config) -> Result<(), PluginError> {
+        self.client = Some(uberduck::Client {
+            http: reqwest::Client::new(),
+            api_key: config.get("UBERDUCK_API_KEY").unwrap_or_default().into(),
+            api_secret: config.get("UBERDUCK_API_SECRET").unwrap_or_default().into(),
+        });
+
+        Ok(())
+    }
+
+    async fn on_message(&self, bot: &Chatbot, msg: &Message) -> Result<(), PluginError> {
+        let uber = self.client.as_ref().ok_or("not initialized")?;
+
+        if !msg.is_mention || !msg.text.contains("speak") {
+            return Ok(());
+        }
+
+        let parent = self.history.parent(msg).ok_or("couldn't find parent")?;
+
+        debug!(text=%parent.text, "speaking");
+
+        let uuid = uber.speak(&parent.text).await?;
+        let url = uber.wait(&uuid).await?;
+        let wav = uber.download(&url).await?;
+
+        let filename = format!("{:0.20}.wav", &parent.text);
+        bot.slack().upload_reply(&parent, &filename, wav).await?;
+
+        Ok(())
+    }
+}
@@ -1,16 +1,15 @@
 use bytes::Bytes;
-use const_format::concatcp;
 use futures::channel::mpsc;
-use futures::sink::SinkExt;
-use futures::stream::{StreamExt, TryStreamExt};
 use futures::Future;
 use serde::Deserialize;
-use serde_json::json;
-use std::borrow::Cow;
-use std::collections::HashMap;
-use tracing::{debug, trace, warn};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::{debug, warn};
 
-const API_URL: &str = "https://slack.com/api/";
+mod web;
+
+pub use web::Web;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -49,26 +48,108 @@ pub struct Message {
 
     #[serde(default)]
     pub is_mention: bool,
+
+    /// Notes attached to the message by the bot itself, e.g. by middleware. These never come from
+    /// Slack.
+    #[serde(skip)]
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// The Slack API calls used by [`Client`]. [`Web`] talks to Slack itself; other implementations
+/// can stand in for it, e.g. in tests.
+#[async_trait::async_trait]
+pub trait Backend: Send + Sync {
+    async fn post(
+        &self,
+        channel: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<(), Error>;
+
+    /// Posts a message like [`Backend::post`], returning its timestamp so that it can be edited
+    /// with [`Backend::update`].
+    async fn post_updatable(
+        &self,
+        channel: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<Timestamp, Error>;
+
+    /// Replaces the text of a message posted by the bot.
+    async fn update(&self, channel: &str, ts: &Timestamp, text: &str) -> Result<(), Error>;
+
+    async fn post_ephemeral(
+        &self,
+        channel: &str,
+        user: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<(), Error>;
+
+    async fn emoji_list(&self) -> Result<Vec<String>, Error>;
+
+    async fn react(&self, message: &Message, emoji: &str) -> Result<(), Error>;
+
+    async fn channel_ids(&self) -> Result<Vec<String>, Error>;
+
+    async fn channel_history(&self, channel_id: &str) -> Result<Vec<Message>, Error>;
+
+    async fn replies(&self, channel_id: &str, ts: &str) -> Result<Vec<Message>, Error>;
+
+    async fn display_name(&self, user_id: &str) -> Result<String, Error>;
+
+    /// Returns the IDs of the users in a user group.
+    async fn usergroup_members(&self, usergroup_id: &str) -> Result<Vec<String>, Error>;
+
+    async fn upload_reply(
+        &self,
+        parent: &Message,
+        filename: &str,
+        content: Bytes,
+    ) -> Result<(), Error>;
+
+    /// Sends incoming messages to `tx` until the connection ends, or until `closed` becomes true,
+    /// in which case the connection should be closed cleanly.
+    async fn events(
+        &self,
+        tx: mpsc::UnboundedSender<Message>,
+        bot_user_id: &str,
+        closed: watch::Receiver<bool>,
+    ) -> Result<(), Error>;
 }
 
 #[derive(Clone)]
 pub struct Client {
-    http: reqwest::Client,
-    app_token: String,
-    bot_token: String,
+    backend: Arc<dyn Backend>,
     bot_user_id: String,
+    closed: Arc<watch::Sender<bool>>,
 }
 
 impl Client {
     pub async fn new(app_token: String, bot_token: String) -> Result<Self, Error> {
-        let http = reqwest::Client::new();
-
-        Ok(Self {
-            bot_user_id: bot_user_id(&http, &bot_token).await?,
-            http,
-            app_token,
-            bot_token,
-        })
+        let web = Web::new(app_token, bot_token);
+
+        let bot_user_id = web.bot_user_id().await?;
+        Ok(Self::with_backend(Arc::new(web), bot_user_id))
+    }
+
+    pub fn with_backend(backend: Arc<dyn Backend>, bot_user_id: String) -> Self {
+        let (closed, _) = watch::channel(false);
+
+        Self {
+            backend,
+            bot_user_id,
+            closed: Arc::new(closed),
+        }
+    }
+
+    /// Returns a client that uses another backend, e.g. one wrapping [`Client::backend`], but is
+    /// otherwise the same as this one.
+    pub fn with_backend_replaced(&self, backend: Arc<dyn Backend>) -> Self {
+        Self {
+            backend,
+            ..self.clone()
+        }
     }
 
     pub async fn post(
@@ -77,51 +158,43 @@ impl Client {
         text: &str,
         parent: Option<&Timestamp>,
     ) -> Result<(), Error> {
-        let req = json!({
-            "channel": channel,
-            "text": text,
-            "thread_ts": parent,
-        });
-
-        let body = self
-            .http
-            .post(concatcp!(API_URL, "chat.postMessage"))
-            .bearer_auth(&self.bot_token)
-            .json(&req)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        deserialize(&body)?;
-
-        Ok(())
+        self.backend.post(channel, text, parent).await
     }
 
-    pub fn bot_user_id(&self) -> &str {
-        &self.bot_user_id
+    /// Posts a message, returning its timestamp so that it can be edited with [`Client::update`].
+    pub async fn post_updatable(
+        &self,
+        channel: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<Timestamp, Error> {
+        self.backend.post_updatable(channel, text, parent).await
     }
 
-    pub async fn event_url(&self) -> Result<String, Error> {
-        #[derive(Debug, Deserialize)]
-        struct Response {
-            url: String,
-        }
+    /// Replaces the text of a message posted by the bot.
+    pub async fn update(&self, channel: &str, ts: &Timestamp, text: &str) -> Result<(), Error> {
+        self.backend.update(channel, ts, text).await
+    }
 
-        let body = self
-            .http
-            .post(concatcp!(API_URL, "apps.connections.open"))
-            .bearer_auth(&self.app_token)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
+    /// Posts a message that is only visible to the given user.
+    pub async fn post_ephemeral(
+        &self,
+        channel: &str,
+        user: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<(), Error> {
+        self.backend
+            .post_ephemeral(channel, user, text, parent)
+            .await
+    }
 
-        let res: Response = deserialize(&body)?;
+    pub fn bot_user_id(&self) -> &str {
+        &self.bot_user_id
+    }
 
-        Ok(res.url)
+    pub fn backend(&self) -> &Arc<dyn Backend> {
+        &self.backend
     }
 
     pub fn messages(&self) -> (impl Future<Output = ()>, mpsc::UnboundedReceiver<Message>) {
@@ -129,256 +202,59 @@ impl Client {
 
         let cli = self.clone();
         let driver = async move {
+            let closed = cli.closed.subscribe();
+
             // TODO: backoff
-            loop {
-                let result = cli.process_messages(tx.clone()).await;
-                warn!(?result, "websocket loop ended, restarting");
+            while !*closed.borrow() {
+                let result = cli
+                    .backend
+                    .events(tx.clone(), &cli.bot_user_id, closed.clone())
+                    .await;
+
+                if !*closed.borrow() {
+                    warn!(?result, "websocket loop ended, restarting");
+                }
             }
+
+            debug!("websocket closed");
         };
 
         (driver, rx)
     }
 
-    // TODO: simply/break out to more fns
-    async fn process_messages(&self, mut tx: mpsc::UnboundedSender<Message>) -> Result<(), Error> {
-        use async_tungstenite::tungstenite;
-
-        #[allow(dead_code)]
-        #[derive(Debug, Deserialize)]
-        #[serde(tag = "type")]
-        #[serde(rename_all = "snake_case")]
-        enum Response {
-            EventsApi {
-                envelope_id: String,
-                payload: Payload,
-            },
-            Hello {
-                num_connections: u32,
-            },
-            Disconnect {
-                reason: String,
-            },
-        }
-
-        #[derive(Debug, Deserialize)]
-        struct Payload {
-            event: Event,
-        }
-
-        #[derive(Debug, Deserialize)]
-        #[serde(tag = "type")]
-        #[serde(rename_all = "snake_case")]
-        enum Event {
-            Message {
-                #[serde(flatten)]
-                message: Option<Message>,
-            },
-            AppMention,
-        }
-
-        let url = self.event_url().await?;
-
-        debug!(%url, "connecting to websocket");
-        let (ws, _) = async_tungstenite::tokio::connect_async(url).await?;
-
-        let (mut sink, mut stream) = ws.split();
-
-        while let Some(response) = stream.try_next().await? {
-            let text = response.into_text()?;
-
-            trace!(%text, "websocket message received");
-
-            if text.starts_with("Ping") {
-                continue;
-            }
-
-            let payload = match serde_json::from_str::<Response>(&text)? {
-                Response::EventsApi {
-                    envelope_id,
-                    payload,
-                } => {
-                    let ack = json!({ "envelope_id": envelope_id });
-                    trace!(%ack, "sending websocket ack");
-                    sink.send(tungstenite::Message::text(ack.to_string()))
-                        .await?;
-                    payload
-                }
-                Response::Disconnect { reason } => {
-                    debug!(%reason, "websocket disconnect sent");
-                    return Err(Error::Api("websocket_disconnect".into()));
-                }
-                _ => continue,
-            };
-
-            if let Event::Message {
-                message: Some(mut msg),
-            } = payload.event
-            {
-                if msg.text.contains(&self.bot_user_id) {
-                    msg.is_mention = true;
-                }
-
-                if !msg.text.is_empty() {
-                    // TODO: we don't care if we drop a few messages, but log this
-                    tx.send(msg).await.ok();
-                }
-            }
-        }
-
-        Ok(())
+    /// Closes the websocket opened by [`Client::messages`], ending its driver. This applies to
+    /// every clone of the client.
+    pub fn close(&self) {
+        self.closed.send_replace(true);
     }
 
     pub async fn emoji_list(&self) -> Result<Vec<String>, Error> {
-        #[derive(Deserialize)]
-        struct Response<'a> {
-            emoji: HashMap<Cow<'a, str>, Cow<'a, str>>,
-        }
-
-        let body = self
-            .http
-            .get(concatcp!(API_URL, "emoji.list"))
-            .bearer_auth(&self.bot_token)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        Ok(deserialize::<Response>(&body)?
-            .emoji
-            .into_keys()
-            .map(|k| k.to_string())
-            .collect())
+        self.backend.emoji_list().await
     }
 
     pub async fn react(&self, message: &Message, emoji: &str) -> Result<(), Error> {
-        let req = json!({
-            "channel": message.channel,
-            "timestamp": message.ts,
-            "name": emoji,
-        });
-
-        let body = self
-            .http
-            .post(concatcp!(API_URL, "reactions.add"))
-            .bearer_auth(&self.bot_token)
-            .json(&req)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        deserialize(&body)?;
-
-        Ok(())
+        self.backend.react(message, emoji).await
     }
 
     /// Returns the IDs for all channels that the bot is currently a member of.
     pub async fn channel_ids(&self) -> Result<Vec<String>, Error> {
-        #[derive(Debug, Deserialize)]
-        struct Response {
-            channels: Vec<Channel>,
-        }
-
-        #[derive(Debug, Deserialize)]
-        struct Channel {
-            id: String,
-        }
-
-        let body = self
-            .http
-            .get(concatcp!(API_URL, "users.conversations"))
-            .bearer_auth(&self.bot_token)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        Ok(deserialize::<Response>(&body)?
-            .channels
-            .into_iter()
-            .map(|c| c.id)
-            .collect())
+        self.backend.channel_ids().await
     }
 
     pub async fn channel_history(&self, channel_id: &str) -> Result<Vec<Message>, Error> {
-        #[derive(Debug, Deserialize)]
-        struct Response {
-            messages: Vec<Message>,
-        }
-
-        let body = self
-            .http
-            .get(concatcp!(API_URL, "conversations.history"))
-            .bearer_auth(&self.bot_token)
-            .query(&[("channel", channel_id)])
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        Ok(add_channel(
-            deserialize::<Response>(&body)?.messages,
-            channel_id,
-        ))
+        self.backend.channel_history(channel_id).await
     }
 
     pub async fn replies(&self, channel_id: &str, ts: &str) -> Result<Vec<Message>, Error> {
-        #[derive(Debug, Deserialize)]
-        struct Response {
-            messages: Vec<Message>,
-        }
-
-        let body = self
-            .http
-            .get(concatcp!(API_URL, "conversations.replies"))
-            .bearer_auth(&self.bot_token)
-            .query(&[("channel", channel_id), ("ts", ts)])
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        Ok(add_channel(
-            deserialize::<Response>(&body)?.messages,
-            channel_id,
-        ))
+        self.backend.replies(channel_id, ts).await
     }
 
     pub async fn display_name(&self, user_id: &str) -> Result<String, Error> {
-        #[derive(Deserialize)]
-        struct Response {
-            profile: Profile,
-        }
-
-        #[derive(Deserialize, Debug)]
-        struct Profile {
-            display_name: String,
-            real_name: String,
-        }
+        self.backend.display_name(user_id).await
+    }
 
-        let body = self
-            .http
-            .get(concatcp!(API_URL, "users.profile.get"))
-            .bearer_auth(&self.bot_token)
-            .query(&[("user", user_id)])
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        let profile = deserialize::<Response>(&body)?.profile;
-
-        if profile.display_name.is_empty() {
-            Ok(profile.real_name)
-        } else {
-            Ok(profile.display_name)
-        }
+    pub async fn usergroup_members(&self, usergroup_id: &str) -> Result<Vec<String>, Error> {
+        self.backend.usergroup_members(usergroup_id).await
     }
 
     pub async fn upload_reply(
@@ -387,94 +263,10 @@ impl Client {
         filename: &str,
         content: Bytes,
     ) -> Result<(), Error> {
-        use reqwest::multipart::{Form, Part};
-
-        let form = Form::new()
-            .text("channels", parent.channel.clone())
-            .text("thread_ts", parent.ts.clone())
-            .text("title", filename.to_string())
-            .part(
-                "file",
-                Part::stream(content).file_name(filename.to_string()),
-            );
-
-        let body = self
-            .http
-            .post(concatcp!(API_URL, "files.upload"))
-            .bearer_auth(&self.bot_token)
-            //.query(&[("channels", &parent.channel), ("thread_ts", &parent.ts)])
-            //.query(&[("filename", filename)])
-            .multipart(form)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        deserialize(&body)?;
-
-        Ok(())
+        self.backend.upload_reply(parent, filename, content).await
     }
 }
 
-fn add_channel(messages: Vec<Message>, channel: &str) -> Vec<Message> {
-    messages
-        .into_iter()
-        .map(|mut msg| {
-            msg.channel = channel.to_string();
-            msg
-        })
-        .collect()
-}
-
-// TODO: find a better name for this
-fn deserialize<'de, T: Deserialize<'de>>(input: &'de str) -> Result<T, Error> {
-    // Slack's API returns HTTP 200 on application failure, and stashes error information directly in
-    // JSON responses to apparently successful calls.
-    #[derive(Deserialize, Debug)]
-    struct Response<T> {
-        ok: bool,
-        error: Option<String>,
-
-        #[serde(flatten)]
-        payload: Option<T>,
-    }
-
-    match serde_json::from_str::<Response<T>>(input)? {
-        Response {
-            ok: true,
-            payload: Some(t),
-            ..
-        } => Ok(t),
-        Response {
-            ok: false,
-            error: Some(e),
-            ..
-        } => Err(Error::Api(e)),
-        _ => Err(Error::Api(format!("unexpected format: {}", input))),
-    }
-}
-
-async fn bot_user_id(http: &reqwest::Client, bot_token: &str) -> Result<String, Error> {
-    #[derive(Debug, Deserialize)]
-    struct Response {
-        user_id: String,
-    }
-
-    let body = http
-        .get(concatcp!(API_URL, "auth.test"))
-        .bearer_auth(bot_token)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
-
-    let res: Response = deserialize(&body)?;
-
-    Ok(res.user_id)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -500,6 +292,7 @@ mod tests {
             reply_count: 0,
             channel: "CLXKXACCF".into(),
             is_mention: false,
+            annotations: BTreeMap::new(),
         };
 
         let file = Bytes::from("hey there");
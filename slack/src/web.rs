@@ -0,0 +1,527 @@
+use crate::{Backend, Error, Message, Timestamp};
+use bytes::Bytes;
+use const_format::concatcp;
+use futures::channel::mpsc;
+use futures::sink::SinkExt;
+use futures::stream::{StreamExt, TryStreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use tokio::sync::watch;
+use tracing::{debug, trace};
+
+const API_URL: &str = "https://slack.com/api/";
+
+/// The real Slack backend, using the Web API and a Socket Mode websocket.
+pub struct Web {
+    http: reqwest::Client,
+    app_token: String,
+    bot_token: String,
+}
+
+impl Web {
+    pub fn new(app_token: String, bot_token: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            app_token,
+            bot_token,
+        }
+    }
+
+    pub async fn bot_user_id(&self) -> Result<String, Error> {
+        bot_user_id(&self.http, &self.bot_token).await
+    }
+
+    pub async fn event_url(&self) -> Result<String, Error> {
+        #[derive(Debug, Deserialize)]
+        struct Response {
+            url: String,
+        }
+
+        let body = self
+            .http
+            .post(concatcp!(API_URL, "apps.connections.open"))
+            .bearer_auth(&self.app_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let res: Response = deserialize(&body)?;
+
+        Ok(res.url)
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for Web {
+    async fn post(
+        &self,
+        channel: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<(), Error> {
+        self.post_updatable(channel, text, parent).await?;
+
+        Ok(())
+    }
+
+    async fn post_updatable(
+        &self,
+        channel: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<Timestamp, Error> {
+        #[derive(Debug, Deserialize)]
+        struct Response {
+            ts: Timestamp,
+        }
+
+        let req = json!({
+            "channel": channel,
+            "text": text,
+            "thread_ts": parent,
+        });
+
+        let body = self
+            .http
+            .post(concatcp!(API_URL, "chat.postMessage"))
+            .bearer_auth(&self.bot_token)
+            .json(&req)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(deserialize::<Response>(&body)?.ts)
+    }
+
+    async fn update(&self, channel: &str, ts: &Timestamp, text: &str) -> Result<(), Error> {
+        let req = json!({
+            "channel": channel,
+            "ts": ts,
+            "text": text,
+        });
+
+        let body = self
+            .http
+            .post(concatcp!(API_URL, "chat.update"))
+            .bearer_auth(&self.bot_token)
+            .json(&req)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        deserialize::<()>(&body)?;
+
+        Ok(())
+    }
+
+    /// Posts a message that is only visible to the given user.
+    async fn post_ephemeral(
+        &self,
+        channel: &str,
+        user: &str,
+        text: &str,
+        parent: Option<&Timestamp>,
+    ) -> Result<(), Error> {
+        let req = json!({
+            "channel": channel,
+            "user": user,
+            "text": text,
+            "thread_ts": parent,
+        });
+
+        let body = self
+            .http
+            .post(concatcp!(API_URL, "chat.postEphemeral"))
+            .bearer_auth(&self.bot_token)
+            .json(&req)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        deserialize::<()>(&body)?;
+
+        Ok(())
+    }
+
+    async fn emoji_list(&self) -> Result<Vec<String>, Error> {
+        #[derive(Deserialize)]
+        struct Response<'a> {
+            emoji: HashMap<Cow<'a, str>, Cow<'a, str>>,
+        }
+
+        let body = self
+            .http
+            .get(concatcp!(API_URL, "emoji.list"))
+            .bearer_auth(&self.bot_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(deserialize::<Response>(&body)?
+            .emoji
+            .into_keys()
+            .map(|k| k.to_string())
+            .collect())
+    }
+
+    async fn react(&self, message: &Message, emoji: &str) -> Result<(), Error> {
+        let req = json!({
+            "channel": message.channel,
+            "timestamp": message.ts,
+            "name": emoji,
+        });
+
+        let body = self
+            .http
+            .post(concatcp!(API_URL, "reactions.add"))
+            .bearer_auth(&self.bot_token)
+            .json(&req)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        deserialize::<()>(&body)?;
+
+        Ok(())
+    }
+
+    /// Returns the IDs for all channels that the bot is currently a member of.
+    async fn channel_ids(&self) -> Result<Vec<String>, Error> {
+        #[derive(Debug, Deserialize)]
+        struct Response {
+            channels: Vec<Channel>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Channel {
+            id: String,
+        }
+
+        let body = self
+            .http
+            .get(concatcp!(API_URL, "users.conversations"))
+            .bearer_auth(&self.bot_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(deserialize::<Response>(&body)?
+            .channels
+            .into_iter()
+            .map(|c| c.id)
+            .collect())
+    }
+
+    async fn channel_history(&self, channel_id: &str) -> Result<Vec<Message>, Error> {
+        #[derive(Debug, Deserialize)]
+        struct Response {
+            messages: Vec<Message>,
+        }
+
+        let body = self
+            .http
+            .get(concatcp!(API_URL, "conversations.history"))
+            .bearer_auth(&self.bot_token)
+            .query(&[("channel", channel_id)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(add_channel(
+            deserialize::<Response>(&body)?.messages,
+            channel_id,
+        ))
+    }
+
+    async fn replies(&self, channel_id: &str, ts: &str) -> Result<Vec<Message>, Error> {
+        #[derive(Debug, Deserialize)]
+        struct Response {
+            messages: Vec<Message>,
+        }
+
+        let body = self
+            .http
+            .get(concatcp!(API_URL, "conversations.replies"))
+            .bearer_auth(&self.bot_token)
+            .query(&[("channel", channel_id), ("ts", ts)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(add_channel(
+            deserialize::<Response>(&body)?.messages,
+            channel_id,
+        ))
+    }
+
+    async fn display_name(&self, user_id: &str) -> Result<String, Error> {
+        #[derive(Deserialize)]
+        struct Response {
+            profile: Profile,
+        }
+
+        #[derive(Deserialize, Debug)]
+        struct Profile {
+            display_name: String,
+            real_name: String,
+        }
+
+        let body = self
+            .http
+            .get(concatcp!(API_URL, "users.profile.get"))
+            .bearer_auth(&self.bot_token)
+            .query(&[("user", user_id)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let profile = deserialize::<Response>(&body)?.profile;
+
+        if profile.display_name.is_empty() {
+            Ok(profile.real_name)
+        } else {
+            Ok(profile.display_name)
+        }
+    }
+
+    async fn usergroup_members(&self, usergroup_id: &str) -> Result<Vec<String>, Error> {
+        #[derive(Debug, Deserialize)]
+        struct Response {
+            users: Vec<String>,
+        }
+
+        let body = self
+            .http
+            .get(concatcp!(API_URL, "usergroups.users.list"))
+            .bearer_auth(&self.bot_token)
+            .query(&[("usergroup", usergroup_id)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        Ok(deserialize::<Response>(&body)?.users)
+    }
+
+    async fn upload_reply(
+        &self,
+        parent: &Message,
+        filename: &str,
+        content: Bytes,
+    ) -> Result<(), Error> {
+        use reqwest::multipart::{Form, Part};
+
+        let form = Form::new()
+            .text("channels", parent.channel.clone())
+            .text("thread_ts", parent.ts.clone())
+            .text("title", filename.to_string())
+            .part(
+                "file",
+                Part::stream(content).file_name(filename.to_string()),
+            );
+
+        let body = self
+            .http
+            .post(concatcp!(API_URL, "files.upload"))
+            .bearer_auth(&self.bot_token)
+            //.query(&[("channels", &parent.channel), ("thread_ts", &parent.ts)])
+            //.query(&[("filename", filename)])
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        deserialize::<()>(&body)?;
+
+        Ok(())
+    }
+
+    // TODO: simply/break out to more fns
+    async fn events(
+        &self,
+        mut tx: mpsc::UnboundedSender<Message>,
+        bot_user_id: &str,
+        mut closed: watch::Receiver<bool>,
+    ) -> Result<(), Error> {
+        use async_tungstenite::tungstenite;
+
+        #[allow(dead_code)]
+        #[derive(Debug, Deserialize)]
+        #[serde(tag = "type")]
+        #[serde(rename_all = "snake_case")]
+        enum Response {
+            EventsApi {
+                envelope_id: String,
+                payload: Payload,
+            },
+            Hello {
+                num_connections: u32,
+            },
+            Disconnect {
+                reason: String,
+            },
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct Payload {
+            event: Event,
+        }
+
+        #[derive(Debug, Deserialize)]
+        #[serde(tag = "type")]
+        #[serde(rename_all = "snake_case")]
+        enum Event {
+            Message {
+                #[serde(flatten)]
+                message: Option<Message>,
+            },
+            AppMention,
+        }
+
+        let url = self.event_url().await?;
+
+        debug!(%url, "connecting to websocket");
+        let (ws, _) = async_tungstenite::tokio::connect_async(url).await?;
+
+        let (mut sink, mut stream) = ws.split();
+
+        loop {
+            let response = tokio::select! {
+                response = stream.try_next() => match response? {
+                    Some(response) => response,
+                    None => break,
+                },
+                _ = closed.changed() => {
+                    debug!("closing websocket");
+                    sink.close().await?;
+                    break;
+                }
+            };
+
+            let text = response.into_text()?;
+
+            trace!(%text, "websocket message received");
+
+            if text.starts_with("Ping") {
+                continue;
+            }
+
+            let payload = match serde_json::from_str::<Response>(&text)? {
+                Response::EventsApi {
+                    envelope_id,
+                    payload,
+                } => {
+                    let ack = json!({ "envelope_id": envelope_id });
+                    trace!(%ack, "sending websocket ack");
+                    sink.send(tungstenite::Message::text(ack.to_string()))
+                        .await?;
+                    payload
+                }
+                Response::Disconnect { reason } => {
+                    debug!(%reason, "websocket disconnect sent");
+                    return Err(Error::Api("websocket_disconnect".into()));
+                }
+                _ => continue,
+            };
+
+            if let Event::Message {
+                message: Some(mut msg),
+            } = payload.event
+            {
+                if msg.text.contains(bot_user_id) {
+                    msg.is_mention = true;
+                }
+
+                if !msg.text.is_empty() {
+                    // TODO: we don't care if we drop a few messages, but log this
+                    tx.send(msg).await.ok();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn add_channel(messages: Vec<Message>, channel: &str) -> Vec<Message> {
+    messages
+        .into_iter()
+        .map(|mut msg| {
+            msg.channel = channel.to_string();
+            msg
+        })
+        .collect()
+}
+
+// TODO: find a better name for this
+fn deserialize<'de, T: Deserialize<'de>>(input: &'de str) -> Result<T, Error> {
+    // Slack's API returns HTTP 200 on application failure, and stashes error information directly in
+    // JSON responses to apparently successful calls.
+    #[derive(Deserialize, Debug)]
+    struct Response<T> {
+        ok: bool,
+        error: Option<String>,
+
+        #[serde(flatten)]
+        payload: Option<T>,
+    }
+
+    match serde_json::from_str::<Response<T>>(input)? {
+        Response {
+            ok: true,
+            payload: Some(t),
+            ..
+        } => Ok(t),
+        Response {
+            ok: false,
+            error: Some(e),
+            ..
+        } => Err(Error::Api(e)),
+        _ => Err(Error::Api(format!("unexpected format: {}", input))),
+    }
+}
+
+async fn bot_user_id(http: &reqwest::Client, bot_token: &str) -> Result<String, Error> {
+    #[derive(Debug, Deserialize)]
+    struct Response {
+        user_id: String,
+    }
+
+    let body = http
+        .get(concatcp!(API_URL, "auth.test"))
+        .bearer_auth(bot_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    let res: Response = deserialize(&body)?;
+
+    Ok(res.user_id)
+}